@@ -0,0 +1,18 @@
+#!/usr/bin/env bash
+
+# Gives payments made before owners were recorded the owner of their reservation.
+# Payments without a reservation keep the empty owner and stay unavailable
+
+set -euo pipefail
+
+reservations=${1:-${RESERVATIONS_DATABASE_URL}}
+payments=${2:-${PAYMENTS_DATABASE_URL}}
+
+psql "$reservations" -v ON_ERROR_STOP=1 \
+  -c "\copy (SELECT payment_uid, username FROM reservation) TO STDOUT WITH (FORMAT csv)" |
+  psql "$payments" -v ON_ERROR_STOP=1 \
+    -c "CREATE TEMP TABLE reservation_owner (payment_uid UUID, username VARCHAR(80))" \
+    -c "\copy reservation_owner FROM pstdin WITH (FORMAT csv)" \
+    -c "UPDATE payment SET username = o.username
+        FROM reservation_owner o
+        WHERE payment.payment_uid = o.payment_uid AND payment.username = ''"
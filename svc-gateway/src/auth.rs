@@ -0,0 +1,182 @@
+use std::{fmt::Display, fs, io, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+
+use crate::{config::AuthConfig, error::ApiError};
+
+/// Header the authenticated user name is forwarded in
+pub const USER_NAME: &str = "X-User-Name";
+
+struct Key {
+    id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// Validates bearer tokens against keys of a local JWKS file
+pub struct Authenticator {
+    keys: Vec<Key>,
+    username_claim: String,
+    issuer: String,
+    audience: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(PathBuf, io::Error),
+    Jwks(String),
+    MissingToken,
+    InvalidToken(String),
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            Self::Jwks(e) => write!(f, "invalid JWKS: {e}"),
+            Self::MissingToken => f.write_str("bearer token is missing"),
+            Self::InvalidToken(e) => write!(f, "invalid token: {e}"),
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let error = match self {
+            Self::MissingToken => {
+                ApiError::new(StatusCode::UNAUTHORIZED, "Bearer token is required")
+            }
+            Self::InvalidToken(_) => {
+                ApiError::new(StatusCode::UNAUTHORIZED, "Bearer token is invalid")
+            }
+            Self::Io(..) | Self::Jwks(_) => ApiError::internal(),
+        };
+        ([(header::WWW_AUTHENTICATE, "Bearer")], error).into_response()
+    }
+}
+
+impl Authenticator {
+    pub fn load(config: &AuthConfig) -> Result<Self, AuthError> {
+        let path = PathBuf::from(&config.jwks_path);
+        let jwks = fs::read_to_string(&path).map_err(|e| AuthError::Io(path, e))?;
+        let jwks: JwkSet =
+            serde_json::from_str(&jwks).map_err(|e| AuthError::Jwks(e.to_string()))?;
+        Self::from_jwks(&jwks, config)
+    }
+
+    pub fn from_jwks(jwks: &JwkSet, config: &AuthConfig) -> Result<Self, AuthError> {
+        let mut keys = Vec::new();
+        for jwk in &jwks.keys {
+            let algorithm = match (&jwk.algorithm, jwk.common.key_algorithm) {
+                (AlgorithmParameters::RSA(_), None | Some(KeyAlgorithm::RS256)) => Algorithm::RS256,
+                (AlgorithmParameters::OctetKey(_), None | Some(KeyAlgorithm::HS256)) => {
+                    Algorithm::HS256
+                }
+                _ => {
+                    log::warn!(
+                        "Skipping JWK {:?}, only RS256 and HS256 keys are supported",
+                        jwk.common.key_id
+                    );
+                    continue;
+                }
+            };
+            let key = DecodingKey::from_jwk(jwk).map_err(|e| AuthError::Jwks(e.to_string()))?;
+            keys.push(Key {
+                id: jwk.common.key_id.clone(),
+                algorithm,
+                key,
+            });
+        }
+        if keys.is_empty() {
+            return Err(AuthError::Jwks("no usable keys".to_owned()));
+        }
+
+        Ok(Self {
+            keys,
+            username_claim: config.username_claim.clone(),
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+        })
+    }
+
+    /// Returns the user name the token was issued to
+    pub fn authenticate(&self, token: &str) -> Result<String, AuthError> {
+        let invalid = |e: &dyn Display| AuthError::InvalidToken(e.to_string());
+
+        let header = jsonwebtoken::decode_header(token).map_err(|e| invalid(&e))?;
+        // the algorithm is bound to the key, so an RSA public key is never used as a secret
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.algorithm == header.alg && (header.kid.is_none() || k.id == header.kid))
+            .ok_or_else(|| invalid(&"no matching key"))?;
+
+        let mut validation = Validation::new(key.algorithm);
+        if !self.issuer.is_empty() {
+            validation.set_issuer(&[&self.issuer]);
+        }
+        if self.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&[&self.audience]);
+        }
+        let claims = jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            token,
+            &key.key,
+            &validation,
+        )
+        .map_err(|e| invalid(&e))?
+        .claims;
+
+        match claims.get(&self.username_claim) {
+            Some(serde_json::Value::String(name)) if !name.is_empty() => Ok(name.clone()),
+            _ => Err(invalid(&format!(
+                "claim {} is missing",
+                self.username_claim
+            ))),
+        }
+    }
+}
+
+/// Requires a valid bearer token on `/api` requests and replaces `X-User-Name` with the user
+/// it was issued to, so handlers and backends only see authenticated names
+pub async fn authenticate(
+    State(auth): State<Arc<Authenticator>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if !req.uri().path().starts_with("/api/") {
+        return next.run(req).await;
+    }
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let username = token
+        .ok_or(AuthError::MissingToken)
+        .and_then(|t| auth.authenticate(t))
+        .and_then(|name| {
+            HeaderValue::from_str(&name).map_err(|e| AuthError::InvalidToken(e.to_string()))
+        });
+    match username {
+        Ok(name) => {
+            req.headers_mut().insert(USER_NAME, name);
+            next.run(req).await
+        }
+        Err(e) => {
+            log::debug!("Rejected {} {}: {e}", req.method(), req.uri().path());
+            e.into_response()
+        }
+    }
+}
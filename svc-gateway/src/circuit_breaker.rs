@@ -0,0 +1,294 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    metrics::metrics,
+    signature::{SignRequest, Signer},
+    trace::TraceRequest,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    /// Failures within `window` after which the circuit opens
+    pub failure_threshold: usize,
+    pub window: Duration,
+    /// Time the circuit stays open before a probe request is let through
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub enum CallError {
+    Open,
+    Request(reqwest::Error),
+}
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Open => f.write_str("circuit breaker is open"),
+            Self::Request(e) => e.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CircuitSnapshot {
+    pub name: &'static str,
+    pub state: CircuitState,
+    pub forced: bool,
+    /// Failures within the configured window
+    pub recent_failures: usize,
+    pub failure_threshold: usize,
+    pub last_error: Option<String>,
+    /// Milliseconds until the next probe request is allowed
+    pub next_probe_in_ms: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    failures: VecDeque<Instant>,
+    opened_at: Option<Instant>,
+    probe_in_flight: bool,
+    forced: bool,
+    last_error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    config: CircuitBreakerConfig,
+    signer: Signer,
+    inner: Mutex<Inner>,
+}
+
+/// Breakers for every downstream the gateway reads from
+#[derive(Debug)]
+pub struct Breakers {
+    pub reservation: CircuitBreaker,
+    pub payment: CircuitBreaker,
+    pub loyalty: CircuitBreaker,
+}
+
+impl Breakers {
+    pub fn all(&self) -> [&CircuitBreaker; 3] {
+        [&self.reservation, &self.payment, &self.loyalty]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CircuitBreaker> {
+        self.all().into_iter().find(|b| b.name == name)
+    }
+}
+
+// Releases a half-open probe slot if the request future is dropped before completion
+struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    finished: bool,
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.breaker.inner.lock().unwrap().probe_in_flight = false;
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, config: CircuitBreakerConfig) -> Self {
+        Self {
+            name,
+            config,
+            signer: Signer::default(),
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                failures: VecDeque::new(),
+                opened_at: None,
+                probe_in_flight: false,
+                forced: false,
+                last_error: None,
+            }),
+        }
+    }
+
+    /// Requests sent through the breaker are signed by `signer`
+    pub fn signing_with(mut self, signer: Signer) -> Self {
+        self.signer = signer;
+        self
+    }
+
+    pub fn snapshot(&self) -> CircuitSnapshot {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        self.prune_failures(&mut inner, now);
+
+        let next_probe_in_ms = match (inner.state, inner.opened_at) {
+            (CircuitState::Open, Some(t)) if !inner.forced => Some(
+                self.config
+                    .cooldown
+                    .saturating_sub(now.duration_since(t))
+                    .as_millis() as u64,
+            ),
+            _ => None,
+        };
+
+        CircuitSnapshot {
+            name: self.name,
+            state: inner.state,
+            forced: inner.forced,
+            recent_failures: inner.failures.len(),
+            failure_threshold: self.config.failure_threshold,
+            last_error: inner.last_error.clone(),
+            next_probe_in_ms,
+        }
+    }
+
+    /// Opens the circuit until `reset` is called
+    pub fn force_open(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        log::warn!("Circuit '{}' forced open", self.name);
+        inner.state = CircuitState::Open;
+        inner.opened_at = Some(Instant::now());
+        inner.forced = true;
+    }
+
+    pub fn reset(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        log::info!("Circuit '{}' reset", self.name);
+        inner.state = CircuitState::Closed;
+        inner.failures.clear();
+        inner.opened_at = None;
+        inner.probe_in_flight = false;
+        inner.forced = false;
+        inner.last_error = None;
+    }
+
+    fn prune_failures(&self, inner: &mut Inner, now: Instant) {
+        while inner
+            .failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.config.window)
+        {
+            inner.failures.pop_front();
+        }
+    }
+
+    /// Returns whether a request may be issued right now
+    pub fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if inner.forced => false,
+            CircuitState::Open => {
+                let cooled_down = inner
+                    .opened_at
+                    .is_none_or(|t| t.elapsed() >= self.config.cooldown);
+                if cooled_down {
+                    log::info!("Circuit '{}' is half-open, sending probe", self.name);
+                    inner.state = CircuitState::HalfOpen;
+                    inner.probe_in_flight = true;
+                }
+                cooled_down
+            }
+            CircuitState::HalfOpen if inner.probe_in_flight => false,
+            CircuitState::HalfOpen => {
+                inner.probe_in_flight = true;
+                true
+            }
+        }
+    }
+
+    pub fn on_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.forced {
+            return;
+        }
+        if inner.state != CircuitState::Closed {
+            log::info!("Circuit '{}' closed", self.name);
+        }
+        inner.state = CircuitState::Closed;
+        inner.failures.clear();
+        inner.opened_at = None;
+        inner.probe_in_flight = false;
+    }
+
+    pub fn on_failure(&self, error: impl Display) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        inner.last_error = Some(error.to_string());
+        inner.failures.push_back(now);
+        self.prune_failures(&mut inner, now);
+        if inner.forced {
+            return;
+        }
+
+        let trip = match inner.state {
+            CircuitState::HalfOpen => true,
+            CircuitState::Closed => inner.failures.len() >= self.config.failure_threshold,
+            CircuitState::Open => false,
+        };
+        if trip {
+            log::warn!("Circuit '{}' opened", self.name);
+            inner.state = CircuitState::Open;
+            inner.opened_at = Some(now);
+        }
+        inner.probe_in_flight = false;
+    }
+
+    /// Sends request through the breaker. Transport errors and 5xx responses count as failures,
+    /// but 5xx responses are still handed back to the caller
+    pub async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response, CallError> {
+        if !self.try_acquire() {
+            log::debug!("Circuit '{}' is open, request rejected", self.name);
+            metrics().downstream(self.name, "rejected", None);
+            return Err(CallError::Open);
+        }
+
+        let mut attempt = Attempt {
+            breaker: self,
+            finished: false,
+        };
+        let started = Instant::now();
+        let resp = req.signed(&self.signer).traced().send().await;
+        attempt.finished = true;
+
+        let (outcome, res) = match resp {
+            Ok(r) if r.status().is_server_error() => {
+                self.on_failure(format!("responded with {}", r.status()));
+                ("server_error", Ok(r))
+            }
+            Ok(r) => {
+                self.on_success();
+                let outcome = if r.status().is_client_error() {
+                    "client_error"
+                } else {
+                    "success"
+                };
+                (outcome, Ok(r))
+            }
+            Err(e) => {
+                self.on_failure(&e);
+                ("error", Err(CallError::Request(e)))
+            }
+        };
+        metrics().downstream(self.name, outcome, Some(started));
+        res
+    }
+}
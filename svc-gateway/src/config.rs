@@ -0,0 +1,682 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Display,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{NaiveDate, Utc};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    circuit_breaker::CircuitBreakerConfig,
+    queue::{JobKind, RetryPolicy},
+};
+
+/// Read when neither `--config` nor `CONFIG_FILE` is given, if it exists
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+pub const CONFIG_PATH_VAR: &str = "CONFIG_FILE";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub downstream: DownstreamConfig,
+    pub http: HttpConfig,
+    pub breaker: BreakerConfig,
+    pub queue: QueueConfig,
+    pub retry: RetryConfig,
+    pub storage: StorageConfig,
+    pub operations: OperationsConfig,
+    pub saga: SagaConfig,
+    pub idempotency: IdempotencyConfig,
+    pub auth: AuthConfig,
+    pub admin: AdminConfig,
+    pub signing: SigningConfig,
+    pub rate_limit: RateLimitConfig,
+    pub validation: ValidationConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub worker_threads: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,
+    pub format: LogFormat,
+    /// Levels of individual modules, e.g. `"bmstu_rsoi_lab2_gateway::queue" = "debug"`
+    pub modules: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per record, request ids are under `mdc`
+    Json,
+}
+
+/// Base URLs of the backend services
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DownstreamConfig {
+    pub reservation: String,
+    pub payment: String,
+    pub loyalty: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    pub connect_timeout_ms: u64,
+    pub read_timeout_ms: u64,
+    pub request_timeout_ms: u64,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout_ms: u64,
+    pub tcp_keepalive_ms: u64,
+    /// Limit for readiness probes of the backends
+    pub ready_timeout_ms: u64,
+}
+
+/// Circuit breaker settings shared by every downstream. `[breaker.<service>]` sections
+/// override them for one downstream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerConfig {
+    pub failure_threshold: usize,
+    pub window_ms: u64,
+    pub cooldown_ms: u64,
+    pub reservation: BreakerOverrides,
+    pub payment: BreakerOverrides,
+    pub loyalty: BreakerOverrides,
+}
+
+/// Settings of one downstream's breaker, unset ones are taken from `[breaker]`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BreakerOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure_threshold: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cooldown_ms: Option<u64>,
+}
+
+impl BreakerConfig {
+    pub fn resolve(&self, overrides: &BreakerOverrides) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failure_threshold: overrides
+                .failure_threshold
+                .unwrap_or(self.failure_threshold),
+            window: Duration::from_millis(overrides.window_ms.unwrap_or(self.window_ms)),
+            cooldown: Duration::from_millis(overrides.cooldown_ms.unwrap_or(self.cooldown_ms)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    pub size: usize,
+    pub workers: usize,
+}
+
+/// Retry policies of queued requests, one `[retry.<job kind>]` section per kind
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    pub loyalty_decrement: RetrySettings,
+    pub payment_cancel: RetrySettings,
+    pub reservation_cancel: RetrySettings,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySettings {
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+    /// Fraction of the backoff that is randomized in both directions
+    pub jitter: f64,
+    pub max_attempts: u32,
+    /// Jobs still undelivered this long after they were queued are dead-lettered
+    pub max_age_secs: u64,
+}
+
+impl RetryConfig {
+    pub fn settings(&self, kind: JobKind) -> &RetrySettings {
+        match kind {
+            JobKind::LoyaltyDecrement => &self.loyalty_decrement,
+            JobKind::PaymentCancel => &self.payment_cancel,
+            JobKind::ReservationCancel => &self.reservation_cancel,
+        }
+    }
+
+    pub fn policy(&self, kind: JobKind) -> RetryPolicy {
+        let settings = self.settings(kind);
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(settings.initial_backoff_ms),
+            max_backoff: Duration::from_millis(settings.max_backoff_ms),
+            multiplier: settings.multiplier,
+            jitter: settings.jitter,
+            max_attempts: settings.max_attempts,
+            max_age: Duration::from_secs(settings.max_age_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    pub queue_log: String,
+    pub dead_letters: String,
+    pub saga_log: String,
+    pub idempotency_log: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OperationsConfig {
+    pub retained: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SagaConfig {
+    /// How often entries of finished sagas are dropped from the journal
+    pub compact_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    pub key_ttl_secs: u64,
+    /// How often expired keys are dropped and the journal is compacted
+    pub sweep_interval_secs: u64,
+}
+
+/// Bearer token authentication of `/api` requests. When disabled, `X-User-Name` sent by the
+/// client is trusted as is
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    pub enabled: bool,
+    /// JWKS file with the keys tokens are signed with, RS256 and HS256 keys are used
+    pub jwks_path: String,
+    /// Claim holding the user name forwarded to the backends
+    pub username_claim: String,
+    /// Expected `iss`, not checked if empty
+    pub issuer: String,
+    /// Expected `aud`, not checked if empty
+    pub audience: String,
+}
+
+/// Access to the `/manage` endpoints that change the gateway state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token such requests must carry. They are all refused if it is empty
+    #[serde(serialize_with = "redacted")]
+    pub token: String,
+}
+
+/// Signing of requests to the backends
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Secret shared with the backends, requests are sent unsigned if it is empty
+    #[serde(serialize_with = "redacted")]
+    pub secret: String,
+}
+
+/// Token buckets for `/api` requests, one per user and one per client IP. Reads (`GET`,
+/// `HEAD`) and writes have separate budgets
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub read: Budget,
+    pub write: Budget,
+    /// Budgets of individual routes, e.g.
+    /// `"/api/v1/reservations" = { write = { burst = 5, per_sec = 0.5 } }`
+    pub routes: BTreeMap<String, RouteBudgets>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    /// Requests allowed at once
+    pub burst: u32,
+    /// Requests added back every second
+    pub per_sec: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteBudgets {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read: Option<Budget>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write: Option<Budget>,
+}
+
+/// Checks of request contents that depend on the deployment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// Accept reservations starting before today
+    pub allow_past_dates: bool,
+}
+
+impl ValidationConfig {
+    /// Earliest day a new reservation may start on
+    pub fn earliest_start(&self) -> NaiveDate {
+        if self.allow_past_dates {
+            NaiveDate::MIN
+        } else {
+            Utc::now().date_naive()
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig {
+                bind: "0.0.0.0:8080".to_owned(),
+                worker_threads: 4,
+            },
+            log: LogConfig {
+                level: "debug".to_owned(),
+                format: LogFormat::Text,
+                modules: BTreeMap::new(),
+            },
+            downstream: DownstreamConfig {
+                reservation: "http://reservation:8070".to_owned(),
+                payment: "http://payment:8060".to_owned(),
+                loyalty: "http://loyalty:8050".to_owned(),
+            },
+            http: HttpConfig {
+                connect_timeout_ms: 2000,
+                read_timeout_ms: 5000,
+                request_timeout_ms: 10000,
+                pool_max_idle_per_host: 32,
+                pool_idle_timeout_ms: 90000,
+                tcp_keepalive_ms: 60000,
+                ready_timeout_ms: 1000,
+            },
+            // loyalty must come back quickly enough for queued requests to be delivered soon
+            // after the service restarts, hence the short cooldown
+            breaker: BreakerConfig {
+                failure_threshold: 5,
+                window_ms: 30000,
+                cooldown_ms: 5000,
+                reservation: BreakerOverrides::default(),
+                payment: BreakerOverrides::default(),
+                loyalty: BreakerOverrides::default(),
+            },
+            queue: QueueConfig {
+                size: 1000,
+                workers: 4,
+            },
+            retry: RetryConfig {
+                loyalty_decrement: RetrySettings {
+                    initial_backoff_ms: 500,
+                    max_backoff_ms: 2000,
+                    multiplier: 2.0,
+                    jitter: 0.2,
+                    max_attempts: 150,
+                    max_age_secs: 5 * 60,
+                },
+                payment_cancel: RetrySettings {
+                    initial_backoff_ms: 500,
+                    max_backoff_ms: 30000,
+                    multiplier: 2.0,
+                    jitter: 0.2,
+                    max_attempts: 200,
+                    max_age_secs: 60 * 60,
+                },
+                reservation_cancel: RetrySettings {
+                    initial_backoff_ms: 500,
+                    max_backoff_ms: 30000,
+                    multiplier: 2.0,
+                    jitter: 0.2,
+                    max_attempts: 200,
+                    max_age_secs: 60 * 60,
+                },
+            },
+            storage: StorageConfig {
+                queue_log: "data/queue.jsonl".to_owned(),
+                dead_letters: "data/dead_letters.json".to_owned(),
+                saga_log: "data/sagas.jsonl".to_owned(),
+                idempotency_log: "data/idempotency.jsonl".to_owned(),
+            },
+            operations: OperationsConfig { retained: 1000 },
+            saga: SagaConfig {
+                compact_interval_secs: 10 * 60,
+            },
+            idempotency: IdempotencyConfig {
+                key_ttl_secs: 24 * 60 * 60,
+                sweep_interval_secs: 10 * 60,
+            },
+            auth: AuthConfig {
+                enabled: false,
+                jwks_path: "jwks.json".to_owned(),
+                username_claim: "preferred_username".to_owned(),
+                issuer: String::new(),
+                audience: String::new(),
+            },
+            admin: AdminConfig {
+                token: String::new(),
+            },
+            signing: SigningConfig {
+                secret: String::new(),
+            },
+            rate_limit: RateLimitConfig {
+                enabled: true,
+                read: Budget {
+                    burst: 100,
+                    per_sec: 50.0,
+                },
+                write: Budget {
+                    burst: 20,
+                    per_sec: 5.0,
+                },
+                routes: BTreeMap::new(),
+            },
+            validation: ValidationConfig {
+                allow_past_dates: false,
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Builds configuration from defaults, then the TOML file, then environment variables
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let file = match path {
+            Some(p) => Some(p.to_path_buf()),
+            None => env::var_os(CONFIG_PATH_VAR)
+                .map(PathBuf::from)
+                .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|p| p.exists())),
+        };
+        let file = match file {
+            Some(p) => Some(fs::read_to_string(&p).map_err(|e| ConfigError::Io(p, e))?),
+            None => None,
+        };
+
+        Self::from_layers(file.as_deref(), |name| env::var(name).ok())
+    }
+
+    /// Layers `file` contents and variables returned by `env` over the defaults
+    pub fn from_layers(
+        file: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut table = toml::Table::try_from(Self::default()).map_err(ConfigError::Serialize)?;
+        if let Some(file) = file {
+            merge(&mut table, file.parse().map_err(ConfigError::Parse)?);
+        }
+        apply_env(&mut table, env)?;
+
+        let config: Self = table.try_into().map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        if SocketAddr::from_str(&self.server.bind).is_err() {
+            errors.push(format!(
+                "server.bind: invalid address {:?}",
+                self.server.bind
+            ));
+        }
+        if self.server.worker_threads == 0 {
+            errors.push("server.worker_threads: must be positive".to_owned());
+        }
+        if LevelFilter::from_str(&self.log.level).is_err() {
+            errors.push(format!("log.level: unknown level {:?}", self.log.level));
+        }
+        for (module, level) in &self.log.modules {
+            if LevelFilter::from_str(level).is_err() {
+                errors.push(format!("log.modules.{module}: unknown level {level:?}"));
+            }
+        }
+        for (name, url) in [
+            ("downstream.reservation", &self.downstream.reservation),
+            ("downstream.payment", &self.downstream.payment),
+            ("downstream.loyalty", &self.downstream.loyalty),
+        ] {
+            match reqwest::Url::parse(url) {
+                Ok(u) if u.scheme() == "http" || u.scheme() == "https" => {}
+                _ => errors.push(format!("{name}: invalid URL {url:?}")),
+            }
+        }
+        if self.auth.enabled {
+            for (name, value) in [
+                ("auth.jwks_path", &self.auth.jwks_path),
+                ("auth.username_claim", &self.auth.username_claim),
+            ] {
+                if value.is_empty() {
+                    errors.push(format!("{name}: must not be empty"));
+                }
+            }
+        }
+        let route_budgets = self.rate_limit.routes.iter().flat_map(|(route, b)| {
+            [
+                (format!("rate_limit.routes.{route}.read"), b.read),
+                (format!("rate_limit.routes.{route}.write"), b.write),
+            ]
+        });
+        for (name, budget) in [
+            ("rate_limit.read".to_owned(), Some(self.rate_limit.read)),
+            ("rate_limit.write".to_owned(), Some(self.rate_limit.write)),
+        ]
+        .into_iter()
+        .chain(route_budgets)
+        {
+            match budget {
+                Some(b) if b.burst == 0 || !b.per_sec.is_finite() || b.per_sec <= 0.0 => {
+                    errors.push(format!("{name}: burst and per_sec must be positive"))
+                }
+                _ => {}
+            }
+        }
+        for (service, overrides) in [
+            ("reservation", &self.breaker.reservation),
+            ("payment", &self.breaker.payment),
+            ("loyalty", &self.breaker.loyalty),
+        ] {
+            let breaker = self.breaker.resolve(overrides);
+            if breaker.failure_threshold == 0 || breaker.window.is_zero() {
+                errors.push(format!(
+                    "breaker.{service}: failure_threshold and window_ms must be positive"
+                ));
+            }
+        }
+        for kind in [
+            JobKind::LoyaltyDecrement,
+            JobKind::PaymentCancel,
+            JobKind::ReservationCancel,
+        ] {
+            let name = kind.name().to_lowercase();
+            let retry = self.retry.settings(kind);
+            if retry.max_attempts == 0 || retry.max_age_secs == 0 {
+                errors.push(format!(
+                    "retry.{name}: max_attempts and max_age_secs must be positive"
+                ));
+            }
+            if retry.initial_backoff_ms > retry.max_backoff_ms {
+                errors.push(format!(
+                    "retry.{name}: initial_backoff_ms must not exceed max_backoff_ms"
+                ));
+            }
+            if !retry.multiplier.is_finite() || retry.multiplier < 1.0 {
+                errors.push(format!("retry.{name}: multiplier must be at least 1"));
+            }
+            if !(0.0..1.0).contains(&retry.jitter) {
+                errors.push(format!("retry.{name}: jitter must be in [0, 1)"));
+            }
+        }
+        for (name, value) in [
+            ("http.connect_timeout_ms", self.http.connect_timeout_ms),
+            ("http.read_timeout_ms", self.http.read_timeout_ms),
+            ("http.request_timeout_ms", self.http.request_timeout_ms),
+            ("http.ready_timeout_ms", self.http.ready_timeout_ms),
+            ("breaker.window_ms", self.breaker.window_ms),
+            (
+                "breaker.failure_threshold",
+                self.breaker.failure_threshold as u64,
+            ),
+            ("queue.size", self.queue.size as u64),
+            ("queue.workers", self.queue.workers as u64),
+            (
+                "saga.compact_interval_secs",
+                self.saga.compact_interval_secs,
+            ),
+            (
+                "idempotency.sweep_interval_secs",
+                self.idempotency.sweep_interval_secs,
+            ),
+        ] {
+            if value == 0 {
+                errors.push(format!("{name}: must be positive"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+}
+
+/// Command line arguments
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub print_config: bool,
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    parsed.config = Some(args.next().ok_or("--config expects a path")?.into())
+                }
+                "--print-config" => parsed.print_config = true,
+                _ => return Err(format!("unknown argument {arg:?}")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+    Env { name: String, value: String },
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            Self::Parse(e) => write!(f, "{e}"),
+            Self::Serialize(e) => write!(f, "{e}"),
+            Self::Env { name, value } => write!(f, "{name}: invalid value {value:?}"),
+            Self::Invalid(errors) => f.write_str(&errors.join("; ")),
+        }
+    }
+}
+
+/// Serializes a secret as `***` so printing the config does not reveal it. An empty value
+/// stays empty to show that it is not set
+fn redacted<S: serde::Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if value.is_empty() { "" } else { "***" })
+}
+
+fn merge(base: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// every `section.key` can be overridden by the `SECTION_KEY` variable, and every key of a
+// sub-section like `breaker.payment` by `BREAKER_PAYMENT_KEY`
+fn apply_env(
+    table: &mut toml::Table,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    for (section, values) in table.iter_mut() {
+        if let Some(values) = values.as_table_mut() {
+            apply_section_env(section, values, &env)?;
+        }
+    }
+    Ok(())
+}
+
+fn apply_section_env(
+    prefix: &str,
+    values: &mut toml::Table,
+    env: &impl Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    // sub-sections may set the keys they leave to their section, with the same types
+    let inherited: Vec<(String, toml::Value)> = values
+        .iter()
+        .filter(|(_, v)| !v.is_table())
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    for (key, value) in values.iter_mut() {
+        let name = format!("{prefix}_{key}").to_uppercase();
+        if let Some(sub) = value.as_table_mut() {
+            for (key, template) in &inherited {
+                if !sub.contains_key(key) && env(&format!("{name}_{key}").to_uppercase()).is_some()
+                {
+                    sub.insert(key.clone(), template.clone());
+                }
+            }
+            apply_section_env(&name, sub, env)?;
+            continue;
+        }
+        let Some(raw) = env(&name) else {
+            continue;
+        };
+        let invalid = || ConfigError::Env {
+            name: name.clone(),
+            value: raw.clone(),
+        };
+        *value = match value {
+            toml::Value::String(_) => toml::Value::String(raw.clone()),
+            toml::Value::Integer(_) => toml::Value::Integer(raw.parse().map_err(|_| invalid())?),
+            toml::Value::Float(_) => toml::Value::Float(raw.parse().map_err(|_| invalid())?),
+            toml::Value::Boolean(_) => toml::Value::Boolean(raw.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+    }
+    Ok(())
+}
@@ -1,16 +1,22 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::Instant};
 
-use axum::http::StatusCode;
 use chrono::{DateTime, NaiveDate};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::{error::ApiError, queue::Service};
 
 pub trait FromJson
 where
     for<'a> Self: Deserialize<'a>,
 {
     async fn try_from_json(r: reqwest::Response) -> Option<Self> {
+        if !r.status().is_success() {
+            log::warn!("Service responded with {}", r.status());
+            return None;
+        }
         match r.json::<Self>().await {
             Err(e) => {
                 log::warn!("Failed to parse service response: {e}");
@@ -19,37 +25,29 @@ where
             Ok(l) => Some(l),
         }
     }
-    async fn from_json(r: reqwest::Response) -> Result<Self, StatusCode> {
+    async fn from_json(r: reqwest::Response) -> Result<Self, ApiError> {
         r.json::<Self>().await.map_err(|e| {
             log::error!("Failed to parse service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            ApiError::internal()
         })
     }
 }
 
-#[derive(Serialize, Deserialize, ToSchema)]
-pub struct ErrorResponse {
-    pub message: String,
-}
-
-impl ErrorResponse {
-    pub fn resp_from_status(s: StatusCode) -> (StatusCode, axum::Json<ErrorResponse>) {
-        (
-            s,
-            axum::Json(ErrorResponse {
-                message: s.to_string(),
-            }),
-        )
-    }
-}
-
 impl FromJson for PaymentInfo {}
 impl FromJson for LoyaltyInfoResponse {}
+impl FromJson for Vec<PaymentInfoServiceResponse> {}
 
-#[derive(Serialize, Deserialize, ToSchema)]
+/// Largest page of hotels that can be requested
+pub const MAX_PAGE_SIZE: usize = 100;
+/// Longest stay a reservation can be made for
+pub const MAX_STAY_DAYS: i64 = 30;
+
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
 #[serde(rename_all = "camelCase")]
 pub struct PaginationRequest {
+    #[validate(range(min = 1))]
     page: usize,
+    #[validate(range(min = 1, max = MAX_PAGE_SIZE))]
     size: usize,
 }
 
@@ -135,6 +133,16 @@ pub struct PaymentInfo {
     pub price: i32,
 }
 
+/// Payment made by the saga, its uid is chosen before the request so the compensation can be
+/// journaled first
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostPaymentServiceRequest {
+    pub payment_uid: Uuid,
+    pub status: PaymentStatus,
+    pub price: i32,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PaymentInfoServiceResponse {
@@ -143,14 +151,56 @@ pub struct PaymentInfoServiceResponse {
     pub price: i32,
 }
 
-#[derive(Deserialize, ToSchema)]
+impl From<PaymentInfoServiceResponse> for PaymentInfo {
+    fn from(value: PaymentInfoServiceResponse) -> Self {
+        Self {
+            status: value.status,
+            price: value.price,
+        }
+    }
+}
+
+/// Dates are checked against the earliest day a reservation may start on
+#[derive(Serialize, Deserialize, ToSchema, Validate)]
 #[serde(rename_all = "camelCase")]
+#[validate(
+    context = NaiveDate,
+    schema(function = "validate_stay", skip_on_field_errors = false)
+)]
 pub struct CreateReservationRequest {
     pub hotel_uid: Uuid,
+    #[validate(custom(function = "validate_start", use_context))]
     pub start_date: NaiveDate,
     pub end_date: NaiveDate,
 }
 
+fn validate_start(start: &NaiveDate, earliest: &NaiveDate) -> Result<(), ValidationError> {
+    if start < earliest {
+        return Err(
+            ValidationError::new("past_date").with_message("must not be in the past".into())
+        );
+    }
+    Ok(())
+}
+
+/// Checks the order and length of the stay. The error is about `endDate`, which is named in
+/// its `field` param
+fn validate_stay(req: &CreateReservationRequest) -> Result<(), ValidationError> {
+    let (code, message) = if req.end_date <= req.start_date {
+        ("date_order", "must be after startDate".to_owned())
+    } else if (req.end_date - req.start_date).num_days() > MAX_STAY_DAYS {
+        (
+            "stay_too_long",
+            format!("must be at most {MAX_STAY_DAYS} days after startDate"),
+        )
+    } else {
+        return Ok(());
+    };
+    let mut error = ValidationError::new(code).with_message(message.into());
+    error.add_param("field".into(), &"endDate");
+    Err(error)
+}
+
 #[derive(Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateReservationResponse {
@@ -166,6 +216,7 @@ pub struct CreateReservationResponse {
 #[derive(Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PostReservationServiceRequest {
+    pub reservation_uid: Uuid,
     pub hotel_uid: Uuid,
     pub payment_uid: Uuid,
     pub start_date: DateTime<chrono::Local>,
@@ -183,6 +234,14 @@ pub struct PostReservationServiceResponse {
     pub status: PaymentStatus,
 }
 
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelReservationResponse {
+    pub reservation_uid: Uuid,
+    /// Сервисы, в которых отмена ещё не применена и ожидает повторной отправки
+    pub pending: Vec<Service>,
+}
+
 #[derive(Serialize, Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct LoyaltyInfoResponse {
@@ -234,3 +293,50 @@ impl Display for PaymentStatus {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HealthStatus {
+    Up,
+    /// Шлюз работает, но часть сервисов недоступна
+    Degraded,
+    Down,
+}
+
+/// Результат проверки одной зависимости шлюза
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    /// Длительность проверки в миллисекундах
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl ComponentHealth {
+    /// Runs `check` and records how long it took
+    pub async fn probe<E: Display>(check: impl Future<Output = Result<(), E>>) -> Self {
+        let started = Instant::now();
+        let res = check.await;
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        let (status, error) = match res {
+            Ok(()) => (HealthStatus::Up, None),
+            Err(e) => (HealthStatus::Down, Some(e.to_string())),
+        };
+        Self {
+            status,
+            latency_ms,
+            error,
+            details: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    pub status: HealthStatus,
+    pub components: BTreeMap<String, ComponentHealth>,
+}
@@ -0,0 +1,245 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ApiError, journal::Journal};
+
+/// Response stored for an idempotency key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredResponse {
+    username: String,
+    key: String,
+    request: serde_json::Value,
+    response: serde_json::Value,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+enum Entry {
+    InProgress { request: serde_json::Value },
+    Completed(StoredResponse),
+}
+
+#[derive(Debug)]
+struct StoreInner {
+    journal: Journal,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+    ttl: Duration,
+}
+
+/// Remembers successful responses by `Idempotency-Key`, scoped to the user. Keys are kept
+/// for `ttl` and survive restarts, expired ones are dropped by [`IdempotencyStore::sweep`]
+#[derive(Debug, Clone)]
+pub struct IdempotencyStore {
+    inner: Arc<StoreInner>,
+}
+
+pub enum Claim {
+    /// Key is new, the request should be executed
+    Acquired(IdempotencyClaim),
+    /// Key was already used with the same request
+    Replay(serde_json::Value),
+}
+
+#[derive(Debug)]
+pub enum IdempotencyError {
+    /// Key was already used with a different request
+    Mismatch,
+    /// Request with the same key is still being executed
+    InProgress,
+    Serialize(serde_json::Error),
+}
+
+impl Display for IdempotencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mismatch => f.write_str("idempotency key was used with a different request"),
+            Self::InProgress => f.write_str("request with this idempotency key is in progress"),
+            Self::Serialize(e) => write!(f, "failed to fingerprint request: {e}"),
+        }
+    }
+}
+
+impl From<IdempotencyError> for ApiError {
+    fn from(value: IdempotencyError) -> Self {
+        match value {
+            IdempotencyError::Mismatch => Self::new(
+                StatusCode::CONFLICT,
+                "Idempotency-Key was already used with a different request",
+            ),
+            IdempotencyError::InProgress => Self::new(
+                StatusCode::CONFLICT,
+                "Request with this Idempotency-Key is still in progress",
+            ),
+            IdempotencyError::Serialize(_) => Self::internal(),
+        }
+    }
+}
+
+impl IdempotencyStore {
+    pub fn open(path: impl AsRef<Path>, ttl: Duration) -> io::Result<Self> {
+        let (journal, stored) = Journal::open::<StoredResponse>(path)?;
+
+        let mut entries = HashMap::new();
+        for s in stored {
+            if is_fresh(s.created_at, ttl) {
+                entries.insert((s.username.clone(), s.key.clone()), Entry::Completed(s));
+            }
+        }
+        journal.rewrite(completed(&entries))?;
+
+        Ok(Self {
+            inner: Arc::new(StoreInner {
+                journal,
+                entries: Mutex::new(entries),
+                ttl,
+            }),
+        })
+    }
+
+    pub fn claim(
+        &self,
+        username: &str,
+        key: &str,
+        request: &impl Serialize,
+    ) -> Result<Claim, IdempotencyError> {
+        let request = serde_json::to_value(request).map_err(IdempotencyError::Serialize)?;
+        let id = (username.to_owned(), key.to_owned());
+
+        let mut entries = self.inner.entries.lock().unwrap();
+        match entries.get(&id) {
+            Some(Entry::Completed(s)) if is_fresh(s.created_at, self.inner.ttl) => {
+                return if s.request == request {
+                    Ok(Claim::Replay(s.response.clone()))
+                } else {
+                    Err(IdempotencyError::Mismatch)
+                };
+            }
+            Some(Entry::InProgress { request: r }) => {
+                return Err(if *r == request {
+                    IdempotencyError::InProgress
+                } else {
+                    IdempotencyError::Mismatch
+                });
+            }
+            _ => {}
+        }
+        entries.insert(
+            id.clone(),
+            Entry::InProgress {
+                request: request.clone(),
+            },
+        );
+
+        Ok(Claim::Acquired(IdempotencyClaim {
+            store: self.clone(),
+            id: Some(id),
+            request,
+        }))
+    }
+
+    /// Forgets expired keys and compacts the journal. Returns the number of dropped keys
+    pub fn sweep(&self) -> io::Result<usize> {
+        let mut entries = self.inner.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|_, e| match e {
+            Entry::Completed(s) => is_fresh(s.created_at, self.inner.ttl),
+            Entry::InProgress { .. } => true,
+        });
+        let dropped = before - entries.len();
+        if dropped > 0 {
+            self.inner.journal.rewrite(completed(&entries))?;
+            log::debug!("Dropped {dropped} expired idempotency keys");
+        }
+        Ok(dropped)
+    }
+
+    /// Sweeps the store every `interval`, so keys that are never reused don't pile up
+    pub async fn sweep_every(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.sweep() {
+                log::error!("Failed to compact idempotency journal: {e}");
+            }
+        }
+    }
+}
+
+fn completed(entries: &HashMap<(String, String), Entry>) -> impl Iterator<Item = &StoredResponse> {
+    entries.values().filter_map(|e| match e {
+        Entry::Completed(s) => Some(s),
+        Entry::InProgress { .. } => None,
+    })
+}
+
+fn is_fresh(created_at: DateTime<Utc>, ttl: Duration) -> bool {
+    (Utc::now() - created_at)
+        .to_std()
+        .map_or(true, |age| age < ttl)
+}
+
+/// Key reserved for a request being executed. Dropping it without `complete` frees the key,
+/// so a failed request can be retried with it
+#[derive(Debug)]
+pub struct IdempotencyClaim {
+    store: IdempotencyStore,
+    id: Option<(String, String)>,
+    request: serde_json::Value,
+}
+
+impl IdempotencyClaim {
+    pub fn complete(mut self, response: &impl Serialize) {
+        let (username, key) = self.id.take().unwrap();
+        let response = match serde_json::to_value(response) {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("Failed to store response for idempotency key {key}: {e}");
+                self.store
+                    .inner
+                    .entries
+                    .lock()
+                    .unwrap()
+                    .remove(&(username, key));
+                return;
+            }
+        };
+        let stored = StoredResponse {
+            username,
+            key,
+            request: std::mem::take(&mut self.request),
+            response,
+            created_at: Utc::now(),
+        };
+
+        // appended under the lock, so a concurrent sweep can't compact the entry away
+        let mut entries = self.store.inner.entries.lock().unwrap();
+        // the response is still kept in memory, only a restart loses it
+        if let Err(e) = self.store.inner.journal.append(&stored) {
+            log::error!("Failed to persist idempotency key {}: {e}", stored.key);
+        }
+        entries.insert(
+            (stored.username.clone(), stored.key.clone()),
+            Entry::Completed(stored),
+        );
+    }
+}
+
+impl Drop for IdempotencyClaim {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.take() {
+            self.store.inner.entries.lock().unwrap().remove(&id);
+        }
+    }
+}
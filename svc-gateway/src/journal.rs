@@ -0,0 +1,113 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Append-only file of JSON lines, synced to disk on every write
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Opens the journal and reads back every entry. Malformed lines, e.g. a torn last line
+    /// after a crash mid-write, are skipped, so callers should `rewrite` the journal before
+    /// appending to it
+    pub fn open<T: DeserializeOwned>(path: impl AsRef<Path>) -> io::Result<(Self, Vec<T>)> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let entries = match File::open(&path) {
+            Ok(f) => Self::read(BufReader::new(f), &path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((
+            Self {
+                path,
+                file: Mutex::new(file),
+            },
+            entries,
+        ))
+    }
+
+    pub fn append<T: Serialize>(&self, entry: &T) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        Self::write_entry(&mut file, entry)?;
+        file.sync_data()
+    }
+
+    /// Atomically replaces journal contents with `entries`
+    pub fn rewrite<T: Serialize>(&self, entries: impl IntoIterator<Item = T>) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        self.replace(&mut file, entries)
+    }
+
+    /// Drops entries `keep` rejects while blocking appends. Returns how many were dropped
+    pub fn retain<T: Serialize + DeserializeOwned>(
+        &self,
+        keep: impl FnMut(&T) -> bool,
+    ) -> io::Result<usize> {
+        let mut file = self.file.lock().unwrap();
+
+        let entries: Vec<T> = Self::read(BufReader::new(File::open(&self.path)?), &self.path);
+        let before = entries.len();
+        let kept: Vec<T> = entries.into_iter().filter(keep).collect();
+        let dropped = before - kept.len();
+        if dropped > 0 {
+            self.replace(&mut file, kept)?;
+        }
+        Ok(dropped)
+    }
+
+    fn replace<T: Serialize>(
+        &self,
+        file: &mut File,
+        entries: impl IntoIterator<Item = T>,
+    ) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for entry in entries {
+            Self::write_entry(&mut tmp, &entry)?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        *file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    fn write_entry<T: Serialize>(file: &mut File, entry: &T) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)
+    }
+
+    fn read<T: DeserializeOwned>(reader: impl BufRead, path: &Path) -> Vec<T> {
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = match line {
+                Ok(l) if l.trim().is_empty() => continue,
+                Ok(l) => l,
+                Err(e) => {
+                    log::error!("Failed to read {}: {e}", path.display());
+                    break;
+                }
+            };
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!("Skipping malformed entry in {}: {e}", path.display()),
+            }
+        }
+        entries
+    }
+}
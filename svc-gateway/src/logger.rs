@@ -1,18 +1,144 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
 use log::LevelFilter;
 use log4rs::{
     append::console::ConsoleAppender,
     config::{Appender, Logger, Root},
+    encode::{json::JsonEncoder, pattern::PatternEncoder, Encode},
     Config, Handle,
 };
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::{LogConfig, LogFormat};
+
+/// Records written while handling a request carry its request id and trace context
+const PATTERN: &str = "{d} {l} {t} [{X(request_id)(-)} {X(trace_id)(-)}/{X(span_id)(-)}] - {m}{n}";
+
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct LogLevels {
+    /// Уровень логирования по умолчанию
+    pub root: String,
+    /// Уровни логирования отдельных модулей
+    pub modules: BTreeMap<String, String>,
+}
 
-pub fn init() -> Handle {
-    let stdout = ConsoleAppender::builder().build();
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct LogLevelsUpdate {
+    pub root: Option<String>,
+    /// `null` возвращает модулю уровень по умолчанию
+    #[serde(default)]
+    pub modules: BTreeMap<String, Option<String>>,
+}
 
-    let config = Config::builder()
+#[derive(Debug)]
+struct Inner {
+    handle: Handle,
+    format: LogFormat,
+    root: LevelFilter,
+    modules: BTreeMap<String, LevelFilter>,
+}
+
+/// Installed logger whose levels can be changed at runtime
+#[derive(Debug, Clone)]
+pub struct Logging {
+    inner: Arc<Mutex<Inner>>,
+}
+
+/// Installs the global logger. `config` must be validated
+pub fn init(config: &LogConfig) -> Logging {
+    let root = LevelFilter::from_str(&config.level).unwrap();
+    let modules = config
+        .modules
+        .iter()
+        .map(|(module, level)| (module.clone(), LevelFilter::from_str(level).unwrap()))
+        .collect();
+    let handle = log4rs::init_config(build(config.format, root, &modules)).unwrap();
+
+    Logging {
+        inner: Arc::new(Mutex::new(Inner {
+            handle,
+            format: config.format,
+            root,
+            modules,
+        })),
+    }
+}
+
+fn build(format: LogFormat, root: LevelFilter, modules: &BTreeMap<String, LevelFilter>) -> Config {
+    let encoder: Box<dyn Encode> = match format {
+        LogFormat::Text => Box::new(PatternEncoder::new(PATTERN)),
+        LogFormat::Json => Box::new(JsonEncoder::new()),
+    };
+    let stdout = ConsoleAppender::builder().encoder(encoder).build();
+
+    Config::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .logger(Logger::builder().build("app", LevelFilter::Debug))
-        .build(Root::builder().appender("stdout").build(LevelFilter::Debug))
-        .unwrap();
+        .loggers(
+            modules
+                .iter()
+                .map(|(module, level)| Logger::builder().build(module, *level)),
+        )
+        .build(Root::builder().appender("stdout").build(root))
+        .unwrap()
+}
+
+impl Logging {
+    pub fn levels(&self) -> LogLevels {
+        let inner = self.inner.lock().unwrap();
+        LogLevels {
+            root: inner.root.to_string(),
+            modules: inner
+                .modules
+                .iter()
+                .map(|(module, level)| (module.clone(), level.to_string()))
+                .collect(),
+        }
+    }
+
+    /// Applies `update` if every level in it is valid, otherwise returns the invalid ones
+    pub fn update(&self, update: LogLevelsUpdate) -> Result<LogLevels, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut parse = |name: &str, level: &str| {
+            LevelFilter::from_str(level)
+                .inspect_err(|_| errors.push(format!("{name}: unknown level {level:?}")))
+                .ok()
+        };
+        let root = update.root.as_deref().map(|level| parse("root", level));
+        let modules: Vec<_> = update
+            .modules
+            .into_iter()
+            .map(|(module, level)| {
+                let level = level.map(|l| parse(&module, &l));
+                (module, level)
+            })
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(Some(root)) = root {
+                inner.root = root;
+            }
+            for (module, level) in modules {
+                match level {
+                    Some(Some(level)) => inner.modules.insert(module, level),
+                    _ => inner.modules.remove(&module),
+                };
+            }
+            let config = build(inner.format, inner.root, &inner.modules);
+            inner.handle.set_config(config);
+        }
 
-    log4rs::init_config(config).unwrap()
+        let levels = self.levels();
+        log::info!("Log levels changed: {levels:?}");
+        Ok(levels)
+    }
 }
@@ -1,18 +1,40 @@
-use std::{pin::Pin, time::Duration};
+use std::{env, net::SocketAddr, process, sync::Arc, time::Duration};
 
-use axum::http::StatusCode;
-use chrono::{DateTime, Utc};
+use admin::Admin;
+use auth::Authenticator;
+use circuit_breaker::{Breakers, CircuitBreaker, CircuitSnapshot, CircuitState};
+use config::{Args, Config, HttpConfig};
 use dto::*;
-use futures::Future;
+use idempotency::IdempotencyStore;
+use logger::{LogLevels, LogLevelsUpdate, Logging};
+use operations::{Operation, OperationStatus, Operations};
+use queue::{DeadLetter, DeadLetterStore, Job, JobKind, JobLog, RetryQueue, Service};
+use rate_limit::RateLimiter;
 use routes::*;
-use tokio::{net::TcpListener, sync::mpsc};
+use saga::Sagas;
+use signature::Signer;
+use tokio::net::TcpListener;
 use utoipa::OpenApi;
 use utoipa_axum::{router::OpenApiRouter, routes};
 use utoipa_swagger_ui::SwaggerUi;
 
+mod admin;
+mod auth;
+mod circuit_breaker;
+mod config;
 mod dto;
+mod error;
+mod idempotency;
+mod journal;
 mod logger;
+mod metrics;
+mod operations;
+mod queue;
+mod rate_limit;
 mod routes;
+mod saga;
+mod signature;
+mod trace;
 
 #[cfg(test)]
 mod tests;
@@ -21,13 +43,24 @@ mod tests;
 #[openapi(
     paths(
         check_health,
+        check_ready,
+        get_metrics,
+        get_log_levels,
+        put_log_levels,
+        get_circuits,
+        open_circuit,
+        reset_circuit,
+        get_dead_letters,
+        replay_dead_letter,
         get_me,
         get_hotels,
         get_loyalty,
         get_reservation,
         get_reservations,
         post_reservation,
-        delete_reservation
+        delete_reservation,
+        get_operations,
+        get_operation
     ),
     components(schemas(
         PaginationResponse,
@@ -41,88 +74,208 @@ mod tests;
         UserInfoResponse,
         ReservationResponse,
         CreateReservationRequest,
-        CreateReservationResponse
+        CreateReservationResponse,
+        CancelReservationResponse,
+        error::ErrorResponse,
+        error::ErrorCode,
+        error::FieldError,
+        ReadinessResponse,
+        LogLevels,
+        LogLevelsUpdate,
+        ComponentHealth,
+        HealthStatus,
+        CircuitSnapshot,
+        CircuitState,
+        DeadLetter,
+        Job,
+        Operation,
+        OperationStatus,
+        JobKind,
+        Service
     ))
 )]
 struct ApiDoc;
 
-pub const SERVICE_ENDPOINT: &str = "0.0.0.0:8080";
-pub const RESERVATION_ENDPOINT: &str = "http://reservation:8070";
-pub const PAYMENT_ENDPOINT: &str = "http://payment:8060";
-pub const LOYALTY_ENDPOINT: &str = "http://loyalty:8050";
-// pub const RESERVATION_ENDPOINT: &str = "http://localhost:8070";
-// pub const PAYMENT_ENDPOINT: &str = "http://localhost:8060";
-// pub const LOYALTY_ENDPOINT: &str = "http://localhost:8050";
-
-pub const MESSAGE_QUEUE_SIZE: usize = 10;
-
 #[derive(Debug, Clone)]
 struct AppState {
-    msg_chan: mpsc::Sender<Message>,
+    config: Arc<Config>,
+    client: reqwest::Client,
+    queue: RetryQueue,
+    sagas: Sagas,
+    operations: Operations,
+    idempotency: IdempotencyStore,
+    logging: Logging,
+    signer: Signer,
+    breakers: Arc<Breakers>,
 }
 
-pub type RequestReturnValue = Pin<Box<dyn Future<Output = Result<(), StatusCode>> + Send>>;
-pub type RequestFn =
-    Box<dyn Fn() -> Pin<Box<dyn Future<Output = Result<(), StatusCode>> + Send>> + Send>;
+fn main() {
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\nUsage: bmstu-rsoi-lab2-gateway [--config <path>] [--print-config]");
+        process::exit(2)
+    });
+    let config = Config::load(args.config.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {e}");
+        process::exit(1)
+    });
+    if args.print_config {
+        print!("{}", toml::to_string_pretty(&config).unwrap());
+        return;
+    }
 
-struct Message {
-    timeout: DateTime<Utc>,
-    request: RequestFn,
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.server.worker_threads)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(Arc::new(config)));
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() {
-    let _logger_handler = logger::init();
+async fn run(config: Arc<Config>) {
+    let logging = logger::init(&config.log);
     log::debug!("Logger initialized. Hello, world!");
 
-    let (w, r) = mpsc::channel(MESSAGE_QUEUE_SIZE);
-    let app = app(w).await;
-
-    log::info!("Listening on {}", SERVICE_ENDPOINT);
-    let listener = TcpListener::bind(SERVICE_ENDPOINT).await.unwrap();
+    let storage = &config.storage;
+    let (job_log, pending) = JobLog::open(&storage.queue_log).expect("Failed to open queue log");
+    let dead_letters =
+        DeadLetterStore::open(&storage.dead_letters).expect("Failed to open dead letter store");
+    let client = http_client(&config.http);
+    let queue = RetryQueue::new(
+        job_log,
+        dead_letters,
+        &config.queue,
+        config.retry.clone(),
+        client.clone(),
+        config.downstream.clone(),
+        Signer::new(&config.signing.secret),
+    );
+    if !pending.is_empty() {
+        log::info!("Replaying {} queued requests", pending.len());
+        queue.restore(pending);
+    }
+    let (sagas, unfinished) =
+        Sagas::open(&storage.saga_log, queue.clone()).expect("Failed to open saga journal");
+    sagas.recover(unfinished);
+    tokio::spawn(
+        sagas
+            .clone()
+            .compact_every(Duration::from_secs(config.saga.compact_interval_secs)),
+    );
+    let idempotency = IdempotencyStore::open(
+        &storage.idempotency_log,
+        Duration::from_secs(config.idempotency.key_ttl_secs),
+    )
+    .expect("Failed to open idempotency store");
+    tokio::spawn(
+        idempotency
+            .clone()
+            .sweep_every(Duration::from_secs(config.idempotency.sweep_interval_secs)),
+    );
+    let app = app(config.clone(), client, queue, sagas, idempotency, logging).await;
 
-    let sender_handle = tokio::spawn(queue_sender(r));
+    log::info!("Listening on {}", config.server.bind);
+    let listener = TcpListener::bind(&config.server.bind).await.unwrap();
 
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
 
-    let (r,) = tokio::join!(sender_handle);
-    r.expect("Failed to join sender handle");
+/// Client shared by all handlers and the retry queue. Timeouts bound how long a hung
+/// downstream can hold a request
+fn http_client(config: &HttpConfig) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_millis(config.connect_timeout_ms))
+        .read_timeout(Duration::from_millis(config.read_timeout_ms))
+        .timeout(Duration::from_millis(config.request_timeout_ms))
+        .pool_max_idle_per_host(config.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_millis(config.pool_idle_timeout_ms))
+        .tcp_keepalive(Duration::from_millis(config.tcp_keepalive_ms))
+        .build()
+        .expect("Failed to build HTTP client")
 }
 
-async fn app(msg_chan: mpsc::Sender<Message>) -> axum::Router {
+async fn app(
+    config: Arc<Config>,
+    client: reqwest::Client,
+    queue: RetryQueue,
+    sagas: Sagas,
+    idempotency: IdempotencyStore,
+    logging: Logging,
+) -> axum::Router {
     let swagger = SwaggerUi::new("/swagger-ui").url("/openapi.json", ApiDoc::openapi());
-    let state = AppState { msg_chan };
+    let auth = config
+        .auth
+        .enabled
+        .then(|| Arc::new(Authenticator::load(&config.auth).expect("Failed to load JWKS")));
+    let limiter = config
+        .rate_limit
+        .enabled
+        .then(|| Arc::new(RateLimiter::new(config.rate_limit.clone())));
+    let admin = Admin::new(&config.admin);
+    let signer = Signer::new(&config.signing.secret);
+    let breaker = |name, overrides| {
+        CircuitBreaker::new(name, config.breaker.resolve(overrides)).signing_with(signer.clone())
+    };
+    let breakers = Arc::new(Breakers {
+        reservation: breaker("reservation", &config.breaker.reservation),
+        payment: breaker("payment", &config.breaker.payment),
+        loyalty: breaker("loyalty", &config.breaker.loyalty),
+    });
+    let state = AppState {
+        operations: Operations::new(config.operations.retained),
+        config,
+        client,
+        queue,
+        sagas,
+        idempotency,
+        logging,
+        breakers,
+        signer,
+    };
     let app = OpenApiRouter::with_openapi(ApiDoc::openapi())
         .routes(routes!(check_health))
+        .routes(routes!(check_ready))
+        .routes(routes!(get_metrics))
+        .routes(routes!(get_log_levels, put_log_levels))
+        .routes(routes!(get_circuits))
+        .routes(routes!(open_circuit))
+        .routes(routes!(reset_circuit))
+        .routes(routes!(get_dead_letters))
+        .routes(routes!(replay_dead_letter))
         .routes(routes!(get_hotels))
         .routes(routes!(get_loyalty))
         .routes(routes!(get_reservations, post_reservation))
         .routes(routes!(delete_reservation, get_reservation))
         .routes(routes!(get_me))
+        .routes(routes!(get_operations))
+        .routes(routes!(get_operation))
         .with_state(state);
+    // runs after authentication, so users are limited by their verified name
+    let app = match limiter {
+        Some(limiter) => app.layer(axum::middleware::from_fn_with_state(
+            limiter,
+            rate_limit::limit,
+        )),
+        None => app,
+    };
+    let app = match auth {
+        Some(auth) => app.layer(axum::middleware::from_fn_with_state(
+            auth,
+            auth::authenticate,
+        )),
+        None => app,
+    };
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(
+            admin,
+            admin::authorize,
+        ))
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(trace::propagate));
 
     axum::Router::from(app).merge(swagger)
 }
-
-async fn queue_sender(mut recv: mpsc::Receiver<Message>) {
-    while let Some(m) = recv.recv().await {
-        loop {
-            match (m.request)().await {
-                Err(s) => {
-                    log::debug!("Failed retry with status {s}, sleeping for 500ms");
-                    tokio::time::sleep(Duration::from_millis(500)).await;
-                }
-                Ok(_) => {
-                    log::debug!("Successfully sent queued request");
-                    break;
-                }
-            }
-            if m.timeout < Utc::now() {
-                log::warn!("Queued request timeout");
-                break;
-            }
-        }
-    }
-}
@@ -0,0 +1,192 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    downstream_requests: IntCounterVec,
+    downstream_duration: HistogramVec,
+    fallbacks: IntCounterVec,
+    queue_jobs: IntCounterVec,
+    compensations: IntCounterVec,
+    rate_limited: IntCounterVec,
+    pub queue_depth: IntGauge,
+    pub dead_letters: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Handled HTTP requests"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling HTTP requests",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let downstream_requests = IntCounterVec::new(
+            Opts::new(
+                "gateway_downstream_requests_total",
+                "Requests to backend services by outcome",
+            ),
+            &["service", "outcome"],
+        )
+        .unwrap();
+        let downstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "gateway_downstream_request_duration_seconds",
+                "Time spent waiting for backend services",
+            ),
+            &["service"],
+        )
+        .unwrap();
+        let fallbacks = IntCounterVec::new(
+            Opts::new(
+                "gateway_fallbacks_total",
+                "Response fields left empty because a backend was unavailable",
+            ),
+            &["route", "field"],
+        )
+        .unwrap();
+        let queue_jobs = IntCounterVec::new(
+            Opts::new(
+                "gateway_queue_jobs_total",
+                "Queued requests that left the retry queue",
+            ),
+            &["kind", "outcome"],
+        )
+        .unwrap();
+        let compensations = IntCounterVec::new(
+            Opts::new(
+                "gateway_saga_compensations_total",
+                "Sagas rolled back by queueing compensations",
+            ),
+            &["saga"],
+        )
+        .unwrap();
+        let rate_limited = IntCounterVec::new(
+            Opts::new(
+                "gateway_rate_limited_total",
+                "Requests rejected with 429 by the limit of a user or client IP",
+            ),
+            &["route", "class", "scope"],
+        )
+        .unwrap();
+        let queue_depth =
+            IntGauge::new("gateway_queue_depth", "Requests waiting in the retry queue").unwrap();
+        let dead_letters =
+            IntGauge::new("gateway_dead_letters", "Requests moved to dead letters").unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry
+            .register(Box::new(downstream_requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(downstream_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(fallbacks.clone())).unwrap();
+        registry.register(Box::new(queue_jobs.clone())).unwrap();
+        registry.register(Box::new(compensations.clone())).unwrap();
+        registry.register(Box::new(rate_limited.clone())).unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        registry.register(Box::new(dead_letters.clone())).unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_duration,
+            downstream_requests,
+            downstream_duration,
+            fallbacks,
+            queue_jobs,
+            compensations,
+            rate_limited,
+            queue_depth,
+            dead_letters,
+        }
+    }
+
+    /// `outcome` is one of `success`, `client_error`, `server_error`, `error` or `rejected`
+    /// (circuit open)
+    pub fn downstream(&self, service: &str, outcome: &str, started: Option<Instant>) {
+        self.downstream_requests
+            .with_label_values(&[service, outcome])
+            .inc();
+        if let Some(started) = started {
+            self.downstream_duration
+                .with_label_values(&[service])
+                .observe(started.elapsed().as_secs_f64());
+        }
+    }
+
+    pub fn fallback(&self, route: &str, field: &str) {
+        self.fallbacks.with_label_values(&[route, field]).inc();
+    }
+
+    pub fn queue_job(&self, kind: &str, outcome: &str) {
+        self.queue_jobs.with_label_values(&[kind, outcome]).inc();
+    }
+
+    pub fn compensation(&self, saga: &str) {
+        self.compensations.with_label_values(&[saga]).inc();
+    }
+
+    pub fn rate_limited(&self, route: &str, class: &str, scope: &str) {
+        self.rate_limited
+            .with_label_values(&[route, class, scope])
+            .inc();
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+/// Records count and latency of every request by route and status
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_owned();
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let resp = next.run(req).await;
+
+    let status = resp.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    let m = metrics();
+    m.http_requests.with_label_values(&labels).inc();
+    m.http_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    resp
+}
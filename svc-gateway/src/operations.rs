@@ -0,0 +1,184 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use serde::Serialize;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{error::ApiError, trace};
+
+/// Largest response body kept as an operation result
+const RESULT_LIMIT: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum OperationStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Operation {
+    pub id: Uuid,
+    pub name: String,
+    #[serde(skip)]
+    pub username: String,
+    pub status: OperationStatus,
+    /// HTTP-код, которым завершилась операция
+    pub http_status: Option<u16>,
+    /// Тело ответа операции
+    pub result: Option<serde_json::Value>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    operations: HashMap<Uuid, Operation>,
+    finished: VecDeque<Uuid>,
+}
+
+/// Runs write workflows as spawned tasks, so they finish (or compensate) even if the client
+/// disconnects, and keeps their outcome for polling. Only the last `retained` finished
+/// operations are kept
+#[derive(Debug, Clone)]
+pub struct Operations {
+    registry: Arc<Mutex<Registry>>,
+    retained: usize,
+}
+
+impl Operations {
+    pub fn new(retained: usize) -> Self {
+        Self {
+            registry: Default::default(),
+            retained,
+        }
+    }
+
+    /// Returns the operation if it was started by `username`
+    pub fn get(&self, id: Uuid, username: &str) -> Option<Operation> {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .operations
+            .get(&id)
+            .filter(|op| op.username == username)
+            .cloned()
+    }
+
+    /// Operations started by `username`, newest first
+    pub fn list(&self, username: &str) -> Vec<Operation> {
+        let registry = self.registry.lock().unwrap();
+        let mut ops: Vec<_> = registry
+            .operations
+            .values()
+            .filter(|op| op.username == username)
+            .cloned()
+            .collect();
+        ops.sort_by_key(|op| std::cmp::Reverse(op.started_at));
+        ops
+    }
+
+    /// Spawns `workflow` and waits for its response. The operation gets the `id` chosen by
+    /// the client, so it can be polled even if the response is lost, or a new one. The
+    /// response carries the id in `X-Operation-Id`
+    pub async fn run(
+        &self,
+        id: Option<Uuid>,
+        name: &str,
+        username: &str,
+        workflow: impl Future<Output = Response> + Send + 'static,
+    ) -> Result<Response, ApiError> {
+        let id = id.unwrap_or_else(Uuid::new_v4);
+        {
+            let mut registry = self.registry.lock().unwrap();
+            if registry.operations.contains_key(&id) {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    format!("Operation {id} already exists"),
+                ));
+            }
+            registry.operations.insert(
+                id,
+                Operation {
+                    id,
+                    name: name.to_owned(),
+                    username: username.to_owned(),
+                    status: OperationStatus::Running,
+                    http_status: None,
+                    result: None,
+                    started_at: Utc::now(),
+                    finished_at: None,
+                },
+            );
+        }
+
+        let this = self.clone();
+        let handle = tokio::spawn(trace::inherit(async move {
+            let resp = match AssertUnwindSafe(workflow).catch_unwind().await {
+                Ok(r) => r,
+                Err(_) => {
+                    log::error!("Operation {id} panicked");
+                    ApiError::internal().into_response()
+                }
+            };
+            this.finish(id, resp).await
+        }));
+
+        let mut resp = handle
+            .await
+            .unwrap_or_else(|_| ApiError::internal().into_response());
+        resp.headers_mut().insert(
+            "X-Operation-Id",
+            HeaderValue::from_str(&id.to_string()).unwrap(),
+        );
+        Ok(resp)
+    }
+
+    async fn finish(&self, id: Uuid, resp: Response) -> Response {
+        let (parts, body) = resp.into_parts();
+        let body = match to_bytes(body, RESULT_LIMIT).await {
+            Ok(b) => b,
+            Err(e) => {
+                log::error!("Failed to read result of operation {id}: {e}");
+                Default::default()
+            }
+        };
+
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(op) = registry.operations.get_mut(&id) {
+            op.status = if parts.status.is_success() {
+                OperationStatus::Succeeded
+            } else {
+                OperationStatus::Failed
+            };
+            op.http_status = Some(parts.status.as_u16());
+            op.result = serde_json::from_slice(&body).ok();
+            op.finished_at = Some(Utc::now());
+            log::debug!(
+                "Operation {} ({id}) finished with {}",
+                op.name,
+                parts.status
+            );
+        }
+        registry.finished.push_back(id);
+        while registry.finished.len() > self.retained {
+            let evicted = registry.finished.pop_front().unwrap();
+            registry.operations.remove(&evicted);
+        }
+
+        Response::from_parts(parts, Body::from(body))
+    }
+}
@@ -0,0 +1,611 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{
+    config::{DownstreamConfig, QueueConfig, RetryConfig},
+    error::ApiError,
+    journal::Journal,
+    metrics::metrics,
+    signature::{SignRequest, Signer},
+    trace::{self, TraceContext, REQUEST_ID, TRACEPARENT},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Service {
+    Reservation,
+    Payment,
+    Loyalty,
+}
+
+impl Service {
+    pub fn endpoint(self, downstream: &DownstreamConfig) -> &str {
+        match self {
+            Self::Reservation => &downstream.reservation,
+            Self::Payment => &downstream.payment,
+            Self::Loyalty => &downstream.loyalty,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    /// Fraction of the backoff that is randomized in both directions
+    pub jitter: f64,
+    pub max_attempts: u32,
+    pub max_age: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before the attempt following `attempt` (counting from 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let base = (self.initial_backoff.as_secs_f64() * exp).min(self.max_backoff.as_secs_f64());
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        Duration::from_secs_f64((base * (1.0 + jitter)).max(0.0))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum JobKind {
+    LoyaltyDecrement,
+    PaymentCancel,
+    ReservationCancel,
+}
+
+impl JobKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::LoyaltyDecrement => "LOYALTY_DECREMENT",
+            Self::PaymentCancel => "PAYMENT_CANCEL",
+            Self::ReservationCancel => "RESERVATION_CANCEL",
+        }
+    }
+}
+
+/// Serializable description of a request to be delivered to a downstream service
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: JobKind,
+    pub method: String,
+    pub service: Service,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+    /// Set from the retry policy of the job kind when the job is queued
+    pub deadline: DateTime<Utc>,
+    #[serde(default)]
+    pub attempts: u32,
+    /// Jobs sharing a key are delivered one at a time in the order they were queued
+    #[serde(default)]
+    pub ordering_key: Option<String>,
+    /// 404 means there is nothing to undo, as for compensations of steps that may not have
+    /// happened
+    #[serde(default)]
+    pub missing_ok: bool,
+}
+
+impl Job {
+    /// The job continues the trace of the request that created it
+    pub fn new(kind: JobKind, method: Method, service: Service, path: String) -> Self {
+        let created_at = Utc::now();
+        Self {
+            id: Uuid::new_v4(),
+            kind,
+            method: method.to_string(),
+            service,
+            path,
+            headers: trace::outgoing_headers(),
+            body: None,
+            created_at,
+            deadline: created_at,
+            attempts: 0,
+            ordering_key: None,
+            missing_ok: false,
+        }
+    }
+
+    pub fn allow_missing(mut self) -> Self {
+        self.missing_ok = true;
+        self
+    }
+
+    pub fn ordered_by(mut self, key: &str) -> Self {
+        self.ordering_key = Some(key.to_owned());
+        self
+    }
+
+    fn ordering_key(&self) -> String {
+        self.ordering_key
+            .clone()
+            .unwrap_or_else(|| self.id.to_string())
+    }
+
+    /// Resets attempt counter and age, e.g. when replaying a dead letter
+    pub fn renew(mut self) -> Self {
+        self.created_at = Utc::now();
+        self.attempts = 0;
+        self
+    }
+
+    fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub async fn send(
+        &self,
+        client: &reqwest::Client,
+        downstream: &DownstreamConfig,
+        signer: &Signer,
+    ) -> Result<(), StatusCode> {
+        let method = Method::from_bytes(self.method.as_bytes()).map_err(|e| {
+            log::error!("Queued request {} has invalid method: {e}", self.id);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        let mut req = client.request(
+            method,
+            format!("{}{}", self.service.endpoint(downstream), self.path),
+        );
+        for (name, value) in &self.headers {
+            req = req.header(name, value);
+        }
+        if let Some(body) = &self.body {
+            req = req.json(body);
+        }
+        // signed on every attempt, backends reject a repeated signature as a replay
+        let req = req.signed(signer);
+
+        let resp = req.send().await.map_err(|e| {
+            log::error!("Failed to issue request to {:?} service: {e}", self.service);
+            StatusCode::SERVICE_UNAVAILABLE
+        })?;
+        if self.missing_ok && resp.status() == StatusCode::NOT_FOUND {
+            log::debug!("Queued request {} found nothing to undo", self.id);
+            return Ok(());
+        }
+        resp.error_for_status()
+            .map_err(|e| e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum LogEntry {
+    Enqueued { job: Job },
+    Done { id: Uuid },
+}
+
+/// On-disk log of queued jobs. Every job is written before it is scheduled and marked done
+/// once delivered, so pending jobs survive a restart
+#[derive(Debug)]
+pub struct JobLog {
+    journal: Journal,
+}
+
+impl JobLog {
+    /// Opens the log, returning jobs that were not delivered before the last shutdown.
+    /// The log is compacted so it only holds those jobs
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<Job>)> {
+        let (journal, entries) = Journal::open(path)?;
+
+        let mut pending: Vec<Job> = Vec::new();
+        for entry in entries {
+            match entry {
+                LogEntry::Enqueued { job } => pending.push(job),
+                LogEntry::Done { id } => pending.retain(|j| j.id != id),
+            }
+        }
+        journal.rewrite(
+            pending
+                .iter()
+                .map(|job| LogEntry::Enqueued { job: job.clone() }),
+        )?;
+
+        Ok((Self { journal }, pending))
+    }
+
+    pub fn enqueued(&self, job: &Job) -> io::Result<()> {
+        self.journal
+            .append(&LogEntry::Enqueued { job: job.clone() })
+    }
+
+    pub fn done(&self, id: Uuid) -> io::Result<()> {
+        self.journal.append(&LogEntry::Done { id })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    pub job: Job,
+    pub reason: String,
+    pub failed_at: DateTime<Utc>,
+}
+
+/// Jobs that exhausted their retry policy. Kept in memory and mirrored to disk on every change
+#[derive(Debug)]
+pub struct DeadLetterStore {
+    path: PathBuf,
+    letters: Mutex<Vec<DeadLetter>>,
+}
+
+impl DeadLetterStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let letters = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            path,
+            letters: Mutex::new(letters),
+        })
+    }
+
+    pub fn list(&self) -> Vec<DeadLetter> {
+        self.letters.lock().unwrap().clone()
+    }
+
+    pub fn add(&self, letter: DeadLetter) -> io::Result<()> {
+        let mut letters = self.letters.lock().unwrap();
+        letters.push(letter);
+        self.persist(&letters)
+    }
+
+    pub fn take(&self, id: Uuid) -> io::Result<Option<DeadLetter>> {
+        let mut letters = self.letters.lock().unwrap();
+        let Some(pos) = letters.iter().position(|l| l.job.id == id) else {
+            return Ok(None);
+        };
+        let letter = letters.remove(pos);
+        if let Err(e) = self.persist(&letters) {
+            letters.insert(pos, letter);
+            return Err(e);
+        }
+        Ok(Some(letter))
+    }
+
+    fn persist(&self, letters: &[DeadLetter]) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&serde_json::to_vec_pretty(letters)?)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    /// Queue holds `capacity` jobs already, new ones are rejected until it drains
+    Full,
+    Io(io::Error),
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Full => f.write_str("retry queue is full"),
+            Self::Io(e) => write!(f, "failed to persist queued request: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for QueueError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<QueueError> for ApiError {
+    fn from(value: QueueError) -> Self {
+        match value {
+            QueueError::Full => Self::new(StatusCode::SERVICE_UNAVAILABLE, "Retry queue is full"),
+            QueueError::Io(_) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to queue request for retry",
+            ),
+        }
+    }
+}
+
+/// Space reserved in a [`RetryQueue`]. Space that is not used is given back on drop
+#[derive(Debug)]
+pub struct QueueSlots {
+    queue: RetryQueue,
+    left: usize,
+}
+
+impl QueueSlots {
+    /// Queues the job in one of the reserved places
+    pub fn push(&mut self, mut job: Job) -> Result<(), QueueError> {
+        if self.left == 0 {
+            return Err(QueueError::Full);
+        }
+        job.deadline = job.created_at + self.queue.inner.retry.policy(job.kind).max_age;
+        self.queue.inner.log.enqueued(&job)?;
+        log::debug!(
+            "Queued {} {:?}{} ({})",
+            job.method,
+            job.service,
+            job.path,
+            job.id
+        );
+        self.left -= 1;
+        self.queue.schedule(job, true);
+        Ok(())
+    }
+}
+
+impl Drop for QueueSlots {
+    fn drop(&mut self) {
+        self.queue.inner.lanes.lock().unwrap().reserved -= self.left;
+    }
+}
+
+// Jobs grouped by ordering key. Each non-empty lane has exactly one task delivering its jobs in order
+#[derive(Debug, Default)]
+struct Lanes {
+    jobs: HashMap<String, VecDeque<Job>>,
+    len: usize,
+    /// Space held by [`QueueSlots`] for jobs not pushed yet
+    reserved: usize,
+}
+
+#[derive(Debug)]
+struct QueueInner {
+    log: JobLog,
+    dead_letters: DeadLetterStore,
+    lanes: Mutex<Lanes>,
+    // limits requests in flight, lanes waiting for a backoff don't hold a permit
+    workers: Semaphore,
+    capacity: usize,
+    retry: RetryConfig,
+    client: reqwest::Client,
+    downstream: DownstreamConfig,
+    signer: Signer,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryQueue {
+    inner: Arc<QueueInner>,
+}
+
+impl RetryQueue {
+    pub fn new(
+        log: JobLog,
+        dead_letters: DeadLetterStore,
+        config: &QueueConfig,
+        retry: RetryConfig,
+        client: reqwest::Client,
+        downstream: DownstreamConfig,
+        signer: Signer,
+    ) -> Self {
+        Self {
+            inner: Arc::new(QueueInner {
+                log,
+                dead_letters,
+                lanes: Mutex::new(Lanes::default()),
+                workers: Semaphore::new(config.workers),
+                capacity: config.size,
+                retry,
+                client,
+                downstream,
+                signer,
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lanes.lock().unwrap().len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity
+    }
+
+    pub fn contains(&self, id: Uuid) -> bool {
+        let lanes = self.inner.lanes.lock().unwrap();
+        lanes.jobs.values().flatten().any(|j| j.id == id)
+    }
+
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.inner.dead_letters.list()
+    }
+
+    /// Moves a dead letter back into the queue. Returns `false` if there is no such letter
+    pub fn replay_dead_letter(&self, id: Uuid) -> Result<bool, QueueError> {
+        let Some(letter) = self.inner.dead_letters.take(id)? else {
+            return Ok(false);
+        };
+
+        log::info!("Replaying dead letter {id}");
+        if let Err(e) = self.push(letter.job.clone().renew()) {
+            self.inner.dead_letters.add(letter)?;
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// Persists the job and schedules its delivery. Never waits for the queue to drain
+    pub fn push(&self, job: Job) -> Result<(), QueueError> {
+        self.reserve(1)
+            .inspect_err(|_| log::warn!("Retry queue is full, rejecting {}", job.id))?
+            .push(job)
+    }
+
+    /// Sets aside space for `count` jobs, so a workflow can make sure its follow-up requests
+    /// can be queued before it changes anything
+    pub fn reserve(&self, count: usize) -> Result<QueueSlots, QueueError> {
+        let mut lanes = self.inner.lanes.lock().unwrap();
+        if lanes.len + lanes.reserved + count > self.inner.capacity {
+            return Err(QueueError::Full);
+        }
+        lanes.reserved += count;
+        Ok(QueueSlots {
+            queue: self.clone(),
+            left: count,
+        })
+    }
+
+    /// Schedules jobs replayed from the log. They were accepted before, so capacity is not checked
+    pub fn restore(&self, jobs: Vec<Job>) {
+        for job in jobs {
+            self.schedule(job, false);
+        }
+    }
+
+    fn schedule(&self, job: Job, reserved: bool) {
+        let key = job.ordering_key();
+        let mut lanes = self.inner.lanes.lock().unwrap();
+        if reserved {
+            lanes.reserved -= 1;
+        }
+        lanes.len += 1;
+        let lane = lanes.jobs.entry(key.clone()).or_default();
+        lane.push_back(job);
+        if lane.len() == 1 {
+            tokio::spawn(self.clone().run_lane(key));
+        }
+    }
+
+    async fn run_lane(self, key: String) {
+        loop {
+            let job = {
+                let lanes = self.inner.lanes.lock().unwrap();
+                match lanes.jobs.get(&key).and_then(VecDeque::front) {
+                    Some(job) => job.clone(),
+                    None => return,
+                }
+            };
+
+            let ctx =
+                TraceContext::incoming(job.header_value(REQUEST_ID), job.header_value(TRACEPARENT));
+            trace::scope(ctx, self.deliver(job)).await;
+
+            let mut lanes = self.inner.lanes.lock().unwrap();
+            lanes.len -= 1;
+            let lane = lanes.jobs.get_mut(&key).unwrap();
+            lane.pop_front();
+            if lane.is_empty() {
+                lanes.jobs.remove(&key);
+                return;
+            }
+        }
+    }
+
+    async fn deliver(&self, mut job: Job) {
+        let policy = self.inner.retry.policy(job.kind);
+        let reason = loop {
+            job.attempts += 1;
+            let result = {
+                let _permit = self.inner.workers.acquire().await.unwrap();
+                job.send(
+                    &self.inner.client,
+                    &self.inner.downstream,
+                    &self.inner.signer,
+                )
+                .await
+            };
+            let status = match result {
+                Ok(_) => {
+                    log::debug!("Successfully sent queued request {}", job.id);
+                    break None;
+                }
+                Err(s) => s,
+            };
+
+            // the downstream understood and refused the request, retrying won't help
+            if status.is_client_error()
+                && status != StatusCode::REQUEST_TIMEOUT
+                && status != StatusCode::TOO_MANY_REQUESTS
+            {
+                break Some(format!("rejected with status {status}"));
+            }
+            if job.attempts >= policy.max_attempts {
+                break Some(format!(
+                    "gave up after {} attempts, last status {status}",
+                    job.attempts
+                ));
+            }
+
+            let delay = policy.backoff(job.attempts);
+            if Utc::now() + delay > job.deadline {
+                break Some(format!("expired, last status {status}"));
+            }
+            log::debug!(
+                "Queued request {} failed with status {status}, retrying in {}ms",
+                job.id,
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+        };
+
+        let outcome = if reason.is_some() {
+            "dead_lettered"
+        } else {
+            "delivered"
+        };
+        metrics().queue_job(job.kind.name(), outcome);
+
+        if let Some(reason) = reason {
+            log::warn!("Queued request {} moved to dead letters: {reason}", job.id);
+            let letter = DeadLetter {
+                job: job.clone(),
+                reason,
+                failed_at: Utc::now(),
+            };
+            // job stays in the queue log if the dead letter could not be saved
+            if let Err(e) = self.inner.dead_letters.add(letter) {
+                log::error!("Failed to save dead letter: {e}");
+                return;
+            }
+        }
+        if let Err(e) = self.inner.log.done(job.id) {
+            log::error!("Failed to write queue log: {e}");
+        }
+    }
+}
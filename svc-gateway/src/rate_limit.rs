@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    config::{Budget, RateLimitConfig},
+    error::ApiError,
+    metrics::metrics,
+};
+
+/// Buckets are swept once there are this many, buckets that refilled completely are dropped
+const SWEEP_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Class {
+    Read,
+    Write,
+}
+
+impl Class {
+    fn of(method: &Method) -> Self {
+        if method == Method::GET || method == Method::HEAD {
+            Self::Read
+        } else {
+            Self::Write
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+        }
+    }
+}
+
+/// What a bucket is keyed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    User,
+    Ip,
+}
+
+impl Scope {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::Ip => "ip",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, budget: Budget, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.per_sec).min(budget.burst as f64);
+        self.updated = now;
+    }
+
+    /// Time until the next token is available
+    fn wait(&self, budget: Budget) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / budget.per_sec).max(0.0))
+    }
+}
+
+type BucketKey = (String, Class, Scope, String);
+
+#[derive(Debug)]
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    sweep_at: usize,
+}
+
+/// Token bucket limits per route, request class, user and client IP
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: Mutex::new(Buckets {
+                buckets: HashMap::new(),
+                sweep_at: SWEEP_THRESHOLD,
+            }),
+        }
+    }
+
+    fn budget(&self, route: &str, class: Class) -> Budget {
+        let route = self.config.routes.get(route);
+        match class {
+            Class::Read => route.and_then(|r| r.read).unwrap_or(self.config.read),
+            Class::Write => route.and_then(|r| r.write).unwrap_or(self.config.write),
+        }
+    }
+
+    /// Takes a token from the bucket of every given key. Nothing is taken unless all of them
+    /// have one, otherwise the exhausted scope and the time until it refills are returned
+    pub fn check(
+        &self,
+        route: &str,
+        method: &Method,
+        keys: &[(Scope, &str)],
+        now: Instant,
+    ) -> Result<(), (Scope, Duration)> {
+        let class = Class::of(method);
+        let budget = self.budget(route, class);
+
+        let mut guard = self.buckets.lock().unwrap();
+        let state = &mut *guard;
+        for (scope, id) in keys {
+            let key = (route.to_owned(), class, *scope, (*id).to_owned());
+            let bucket = state.buckets.entry(key).or_insert(Bucket {
+                tokens: budget.burst as f64,
+                updated: now,
+            });
+            bucket.refill(budget, now);
+            if bucket.tokens < 1.0 {
+                return Err((*scope, bucket.wait(budget)));
+            }
+        }
+        for (scope, id) in keys {
+            let key = (route.to_owned(), class, *scope, (*id).to_owned());
+            if let Some(bucket) = state.buckets.get_mut(&key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        if state.buckets.len() >= state.sweep_at {
+            state.buckets.retain(|(route, class, _, _), bucket| {
+                let budget = self.budget(route, *class);
+                bucket.refill(budget, now);
+                bucket.tokens < budget.burst as f64
+            });
+            state.sweep_at = (state.buckets.len() * 2).max(SWEEP_THRESHOLD);
+        }
+        Ok(())
+    }
+}
+
+/// Answers `/api` requests over the limit of their user or client IP with 429
+pub async fn limit(State(limiter): State<Arc<RateLimiter>>, req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/api/") {
+        return next.run(req).await;
+    }
+    // unknown routes are cheap to answer, there is nothing to protect
+    let Some(route) = req.extensions().get::<MatchedPath>() else {
+        return next.run(req).await;
+    };
+    let route = route.as_str().to_owned();
+
+    let user = req
+        .headers()
+        .get("X-User-Name")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let ip = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical().to_string());
+    let mut keys = Vec::new();
+    if let Some(user) = &user {
+        keys.push((Scope::User, user.as_str()));
+    }
+    if let Some(ip) = &ip {
+        keys.push((Scope::Ip, ip.as_str()));
+    }
+
+    match limiter.check(&route, req.method(), &keys, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err((scope, wait)) => {
+            log::debug!(
+                "Rate limit of {} exceeded on {} {route}",
+                scope.name(),
+                req.method()
+            );
+            metrics().rate_limited(&route, Class::of(req.method()).name(), scope.name());
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, "Too many requests"),
+            )
+                .into_response()
+        }
+    }
+}
@@ -1,17 +1,54 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
 use axum::{
-    extract::{Path, Query, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    Json,
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
-use chrono::{Duration, NaiveTime, Utc};
+use chrono::NaiveTime;
+use futures::FutureExt;
+use reqwest::Method;
 use uuid::Uuid;
+use validator::{Validate, ValidateArgs};
 
 use crate::{
-    dto::*, AppState, Message, RequestReturnValue, LOYALTY_ENDPOINT, PAYMENT_ENDPOINT,
-    RESERVATION_ENDPOINT,
+    circuit_breaker::{CircuitBreaker, CircuitSnapshot},
+    dto::*,
+    error::{ApiError, CheckStatus, ErrorResponse, Json, Path, Query},
+    idempotency::Claim,
+    logger::{LogLevels, LogLevelsUpdate},
+    metrics::metrics,
+    operations::Operation,
+    queue::{DeadLetter, Job, JobKind, Service},
+    signature::SignRequest,
+    trace::TraceRequest,
+    AppState,
 };
 
+/// Name of the user the request is made for, required by all `/api` routes
+fn username(headers: &HeaderMap) -> Result<&str, ApiError> {
+    headers
+        .get("X-User-Name")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| ApiError::bad_request("X-User-Name header is required"))
+}
+
+/// Id the client chose for its operation in `X-Operation-Id`
+fn operation_id(headers: &HeaderMap) -> Result<Option<Uuid>, ApiError> {
+    headers
+        .get("X-Operation-Id")
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| ApiError::bad_request("X-Operation-Id header must be a UUID"))
+        })
+        .transpose()
+}
+
 #[utoipa::path(
     get,
     path = "/manage/health",
@@ -23,6 +60,261 @@ pub async fn check_health() -> impl IntoResponse {
     StatusCode::OK
 }
 
+#[utoipa::path(
+    get,
+    path = "/manage/metrics",
+    responses(
+        (
+            status = OK,
+            description = "Метрики в формате Prometheus",
+            body = String,
+            content_type = "text/plain; version=0.0.4",
+        ),
+    )
+)]
+pub async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let m = metrics();
+    m.queue_depth.set(state.queue.len() as i64);
+    m.dead_letters.set(state.queue.dead_letters().len() as i64);
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        m.render(),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/log-levels",
+    responses(
+        (
+            status = OK,
+            description = "Текущие уровни логирования",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn get_log_levels(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.logging.levels())
+}
+
+#[utoipa::path(
+    put,
+    path = "/manage/log-levels",
+    request_body = LogLevelsUpdate,
+    responses(
+        (
+            status = OK,
+            description = "Уровни логирования изменены",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+        (status = BAD_REQUEST, description = "Неизвестный уровень логирования", body = ErrorResponse),
+        (status = UNAUTHORIZED, body = ErrorResponse, description = "Нужен токен администратора"),
+    )
+)]
+pub async fn put_log_levels(
+    State(state): State<AppState>,
+    Json(update): Json<LogLevelsUpdate>,
+) -> impl IntoResponse {
+    match state.logging.update(update) {
+        Ok(levels) => Json(levels).into_response(),
+        Err(errors) => ApiError::bad_request(errors.join("; ")).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/ready",
+    responses(
+        (
+            status = OK,
+            description = "Шлюз готов обрабатывать запросы. При недоступности части сервисов статус DEGRADED",
+            body = ReadinessResponse,
+            content_type = "application/json",
+        ),
+        (
+            status = SERVICE_UNAVAILABLE,
+            description = "Очередь повторных запросов переполнена",
+            body = ReadinessResponse,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn check_ready(State(state): State<AppState>) -> impl IntoResponse {
+    let timeout = Duration::from_millis(state.config.http.ready_timeout_ms);
+    // circuit breakers are bypassed, the probe reports actual reachability
+    let probe = |service: Service| {
+        let req = state
+            .client
+            .get(format!(
+                "{}/manage/ready",
+                service.endpoint(&state.config.downstream)
+            ))
+            .timeout(timeout)
+            .traced();
+        ComponentHealth::probe(async move {
+            let r = req.send().await.map_err(|e| e.to_string())?;
+            if r.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("responded with {}", r.status()))
+            }
+        })
+    };
+    let (reservation, payment, loyalty) = futures::join!(
+        probe(Service::Reservation),
+        probe(Service::Payment),
+        probe(Service::Loyalty)
+    );
+
+    let (depth, capacity) = (state.queue.len(), state.queue.capacity());
+    let mut queue = ComponentHealth::probe(async {
+        if depth < capacity {
+            Ok(())
+        } else {
+            Err("retry queue is full")
+        }
+    })
+    .await;
+    queue.details = Some(serde_json::json!({ "depth": depth, "capacity": capacity }));
+
+    // the gateway keeps serving when backends are down, only a full queue stops it from
+    // accepting cancellations
+    let status = if queue.status == HealthStatus::Down {
+        HealthStatus::Down
+    } else if [&reservation, &payment, &loyalty]
+        .iter()
+        .any(|c| c.status == HealthStatus::Down)
+    {
+        HealthStatus::Degraded
+    } else {
+        HealthStatus::Up
+    };
+    let resp = ReadinessResponse {
+        status,
+        components: BTreeMap::from([
+            ("reservation".to_owned(), reservation),
+            ("payment".to_owned(), payment),
+            ("loyalty".to_owned(), loyalty),
+            ("queue".to_owned(), queue),
+        ]),
+    };
+    let code = match status {
+        HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+    (code, Json(resp))
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/circuits",
+    responses(
+        (
+            status = OK,
+            description = "Состояние circuit breaker'ов сервисов",
+            body = Vec<CircuitSnapshot>,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn get_circuits(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.breakers.all().map(CircuitBreaker::snapshot))
+}
+
+#[utoipa::path(
+    post,
+    path = "/manage/circuits/{name}/open",
+    responses(
+        (status = OK, body = CircuitSnapshot, description = "Circuit breaker принудительно открыт"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "Сервис не найден"),
+        (status = UNAUTHORIZED, body = ErrorResponse, description = "Нужен токен администратора"),
+    ),
+    params(
+        ("name", Path, description = "Имя сервиса: reservation, payment или loyalty"),
+    ),
+)]
+pub async fn open_circuit(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let breaker = state
+        .breakers
+        .get(&name)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown service {name}")))?;
+    breaker.force_open();
+
+    Ok(Json(breaker.snapshot()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/manage/circuits/{name}/reset",
+    responses(
+        (status = OK, body = CircuitSnapshot, description = "Circuit breaker сброшен"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "Сервис не найден"),
+        (status = UNAUTHORIZED, body = ErrorResponse, description = "Нужен токен администратора"),
+    ),
+    params(
+        ("name", Path, description = "Имя сервиса: reservation, payment или loyalty"),
+    ),
+)]
+pub async fn reset_circuit(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    let breaker = state
+        .breakers
+        .get(&name)
+        .ok_or_else(|| ApiError::not_found(format!("Unknown service {name}")))?;
+    breaker.reset();
+
+    Ok(Json(breaker.snapshot()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/dead-letters",
+    responses(
+        (
+            status = OK,
+            description = "Запросы, которые не удалось доставить из очереди",
+            body = Vec<DeadLetter>,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn get_dead_letters(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.queue.dead_letters())
+}
+
+#[utoipa::path(
+    post,
+    path = "/manage/dead-letters/{id}/replay",
+    responses(
+        (status = ACCEPTED, description = "Запрос возвращён в очередь"),
+        (status = NOT_FOUND, body = ErrorResponse, description = "Запрос не найден"),
+        (status = UNAUTHORIZED, body = ErrorResponse, description = "Нужен токен администратора"),
+    ),
+    params(
+        ("id", Path, description = "Идентификатор запроса в очереди"),
+    ),
+)]
+pub async fn replay_dead_letter(
+    State(state): State<AppState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    match state.queue.replay_dead_letter(id) {
+        Ok(true) => Ok(StatusCode::ACCEPTED),
+        Ok(false) => Err(ApiError::not_found(format!("Dead letter {id} not found"))),
+        Err(e) => {
+            log::error!("Failed to replay dead letter {id}: {e}");
+            Err(e.into())
+        }
+    }
+}
+
 #[utoipa::path(
     get,
     path = "/api/v1/hotels",
@@ -33,6 +325,7 @@ pub async fn check_health() -> impl IntoResponse {
             body = PaginationResponse,
             content_type = "application/json",
         ),
+        (status = BAD_REQUEST, description = "Недопустимые параметры страницы", body = ErrorResponse),
     ),
     params(
         ("page", Query, description="Количество страниц"),
@@ -40,24 +333,35 @@ pub async fn check_health() -> impl IntoResponse {
     ),
 )]
 pub async fn get_hotels(
+    State(state): State<AppState>,
     Query(pagination): Query<PaginationRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let client = reqwest::Client::new();
+) -> Result<impl IntoResponse, ApiError> {
+    pagination.validate()?;
+    let client = &state.client;
 
-    let resp = client
-        .get(format!("{RESERVATION_ENDPOINT}/api/v1/hotels"))
-        .query(&pagination)
-        .send()
+    let resp = state
+        .breakers
+        .reservation
+        .send(
+            client
+                .get(format!(
+                    "{}/api/v1/hotels",
+                    state.config.downstream.reservation
+                ))
+                .query(&pagination),
+        )
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to reservation service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
+            ApiError::unavailable("Reservation")
         })?
+        .checked()
+        .await?
         .json::<PaginationResponse>()
         .await
         .map_err(|e| {
             log::error!("Failed to parse reservation service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            ApiError::internal()
         })?;
 
     Ok(Json(resp))
@@ -78,69 +382,63 @@ pub async fn get_hotels(
         ("X-User-Name", Header, description="Имя пользователя"),
     ),
 )]
-pub async fn get_me(headers: HeaderMap) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+pub async fn get_me(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
 
-    let client = reqwest::Client::new();
-    let loyalty = client
-        .get(format!("{LOYALTY_ENDPOINT}/api/v1/loyalty"))
-        .header("X-User-Name", username)
-        .send()
+    let client = &state.client;
+    let loyalty = state
+        .breakers
+        .loyalty
+        .send(
+            client
+                .get(format!(
+                    "{}/api/v1/loyalty",
+                    state.config.downstream.loyalty
+                ))
+                .header("X-User-Name", username),
+        )
         .await;
     let loyalty = match loyalty {
         Err(e) => {
-            log::warn!("Failed to issue request to reservation service: {e}");
+            log::warn!("Failed to issue request to loyalty service: {e}");
             None
         }
-        Ok(l) if l.status().is_client_error() => return Err(l.status()),
+        Ok(l) if l.status().is_client_error() => return Err(ApiError::from_downstream(l).await),
         Ok(l) => LoyaltyInfoResponse::try_from_json(l).await,
     };
+    if loyalty.is_none() {
+        metrics().fallback("/api/v1/me", "loyalty");
+    }
 
-    let reservations = client
-        .get(format!("{RESERVATION_ENDPOINT}/api/v1/reservations"))
-        .header("X-User-Name", username)
-        .send()
+    let reservations = state
+        .breakers
+        .reservation
+        .send(
+            client
+                .get(format!(
+                    "{}/api/v1/reservations",
+                    state.config.downstream.reservation
+                ))
+                .header("X-User-Name", username),
+        )
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to reservation service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
+            ApiError::unavailable("Reservation")
         })?
-        .error_for_status()
-        .map_err(|e| e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))?
+        .checked()
+        .await?
         .json::<Vec<ReservationServiceResponse>>()
         .await
         .map_err(|e| {
             log::error!("Failed to parse reservation service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            ApiError::internal()
         })?;
 
-    let reservations = reservations
-        .into_iter()
-        .map(|el| async {
-            let payment_info = reqwest::Client::new()
-                .get(format!(
-                    "{}/api/v1/payment/{}",
-                    PAYMENT_ENDPOINT, el.payment_uid
-                ))
-                .send()
-                .await;
-            let payment_info = match payment_info {
-                Err(e) => {
-                    log::warn!("Failed to issue request to reservation service: {e}");
-                    None
-                }
-                Ok(p) => PaymentInfo::try_from_json(p).await,
-            };
-
-            ReservationResponse::from_svc_responses(el, payment_info)
-        })
-        .collect::<Vec<_>>();
-
-    let reservations = futures::future::join_all(reservations).await;
+    let reservations = with_payments(&state, username, reservations, "/api/v1/me").await;
 
     Ok((
         StatusCode::OK,
@@ -151,6 +449,63 @@ pub async fn get_me(headers: HeaderMap) -> Result<impl IntoResponse, StatusCode>
     ))
 }
 
+/// Largest batch accepted by the payment service
+const PAYMENT_BATCH_SIZE: usize = 100;
+
+/// Attaches payments of `username` to `reservations`, looking them up in batches instead of
+/// one request per reservation. A payment missing from the response, or lost with a failed batch, is left
+/// empty and counted as a fallback of `route`
+async fn with_payments(
+    state: &AppState,
+    username: &str,
+    reservations: Vec<ReservationServiceResponse>,
+    route: &str,
+) -> Vec<ReservationResponse> {
+    let uids: Vec<Uuid> = reservations.iter().map(|r| r.payment_uid).collect();
+    let batches = uids.chunks(PAYMENT_BATCH_SIZE).map(|uids| async move {
+        let resp = state
+            .breakers
+            .payment
+            .send(
+                state
+                    .client
+                    .post(format!(
+                        "{}/api/v1/payment/batch",
+                        state.config.downstream.payment
+                    ))
+                    .header("X-User-Name", username)
+                    .json(&serde_json::json!({ "paymentUids": uids })),
+            )
+            .await;
+        match resp {
+            Err(e) => {
+                log::warn!("Failed to issue request to payment service: {e}");
+                Vec::new()
+            }
+            Ok(r) => Vec::<PaymentInfoServiceResponse>::try_from_json(r)
+                .await
+                .unwrap_or_default(),
+        }
+    });
+    let mut payments: HashMap<Uuid, PaymentInfo> = futures::future::join_all(batches)
+        .await
+        .into_iter()
+        .flatten()
+        .map(|p| (p.payment_uid, PaymentInfo::from(p)))
+        .collect();
+
+    reservations
+        .into_iter()
+        .map(|el| {
+            let payment_info = payments.remove(&el.payment_uid);
+            if payment_info.is_none() {
+                metrics().fallback(route, "payment");
+            }
+            ReservationResponse::from_svc_responses(el, payment_info)
+        })
+        .collect()
+}
+
 #[utoipa::path(
     get,
     path = "/api/v1/reservations",
@@ -166,58 +521,39 @@ pub async fn get_me(headers: HeaderMap) -> Result<impl IntoResponse, StatusCode>
         ("X-User-Name", Header, description = "Имя пользователя")
     ),
 )]
-pub async fn get_reservations(headers: HeaderMap) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+pub async fn get_reservations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
 
-    let resp = reqwest::Client::new()
-        .get(format!("{RESERVATION_ENDPOINT}/api/v1/reservations"))
-        .header("X-User-Name", username)
-        .send()
+    let resp = state
+        .breakers
+        .reservation
+        .send(
+            state
+                .client
+                .get(format!(
+                    "{}/api/v1/reservations",
+                    state.config.downstream.reservation
+                ))
+                .header("X-User-Name", username),
+        )
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to reservation service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
+            ApiError::unavailable("Reservation")
         })?
+        .checked()
+        .await?
         .json::<Vec<ReservationServiceResponse>>()
         .await
         .map_err(|e| {
             log::error!("Failed to parse reservation service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            ApiError::internal()
         })?;
 
-    let resp = resp
-        .into_iter()
-        .map(|el| async {
-            let payment_info = reqwest::Client::new()
-                .get(format!(
-                    "{}/api/v1/payment/{}",
-                    PAYMENT_ENDPOINT, el.payment_uid
-                ))
-                .send()
-                .await;
-            let payment_info = match payment_info {
-                Err(e) => {
-                    log::warn!("Failed to issue request to payment service: {e}");
-                    None
-                }
-                Ok(p) => match p.json::<PaymentInfo>().await {
-                    Err(e) => {
-                        log::warn!("Failed to parse payment service response: {e}");
-                        None
-                    }
-                    Ok(p) => Some(p),
-                },
-            };
-
-            ReservationResponse::from_svc_responses(el, payment_info)
-        })
-        .collect::<Vec<_>>();
-
-    let resp = futures::future::join_all(resp).await;
+    let resp = with_payments(&state, username, resp, "/api/v1/reservations").await;
 
     Ok(Json(resp))
 }
@@ -232,45 +568,103 @@ pub async fn get_reservations(headers: HeaderMap) -> Result<impl IntoResponse, S
             body = CreateReservationResponse,
             content_type = "application/json",
         ),
+        (status = BAD_REQUEST, description = "Недопустимые даты бронирования", body = ErrorResponse),
+        (
+            status = CONFLICT,
+            description = "Ключ идемпотентности использован с другим запросом, запрос ещё выполняется или операция с таким идентификатором уже есть",
+            body = ErrorResponse,
+        ),
+        (status = SERVICE_UNAVAILABLE, description = "Сервис недоступен", body = ErrorResponse),
     ),
     params(
-        ("X-User-Name", Header, description = "Имя пользователя")
+        ("X-User-Name", Header, description = "Имя пользователя"),
+        (
+            "Idempotency-Key" = Option<String>,
+            Header,
+            description = "Ключ идемпотентности: повторный запрос с тем же ключом вернёт сохранённый ответ"
+        ),
+        (
+            "X-Operation-Id" = Option<Uuid>,
+            Header,
+            description = "Идентификатор операции, выбранный клиентом: по нему можно узнать результат, если ответ потерян"
+        ),
     ),
 )]
 pub async fn post_reservation(
+    State(state): State<AppState>,
     headers: HeaderMap,
     Json(req): Json<CreateReservationRequest>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST.into_response())?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST.into_response())?;
+) -> Result<impl IntoResponse, Response> {
+    let username = username(&headers)
+        .map_err(IntoResponse::into_response)?
+        .to_owned();
+    req.validate_with_args(&state.config.validation.earliest_start())
+        .map_err(|e| ApiError::from(e).into_response())?;
+    let operation_id = operation_id(&headers).map_err(IntoResponse::into_response)?;
+    let idempotency_key = headers
+        .get("Idempotency-Key")
+        .map(|k| k.to_str())
+        .transpose()
+        .map_err(|_| ApiError::bad_request("Idempotency-Key header is invalid").into_response())?;
+
+    let claim = match idempotency_key {
+        Some(key) => match state.idempotency.claim(&username, key, &req) {
+            Ok(Claim::Acquired(claim)) => Some(claim),
+            Ok(Claim::Replay(resp)) => {
+                log::debug!("Replaying response for idempotency key {key}");
+                return Ok(([("Idempotent-Replayed", "true")], Json(resp)).into_response());
+            }
+            Err(e) => {
+                log::warn!("Rejecting request with idempotency key {key}: {e}");
+                return Err(ApiError::from(e).into_response());
+            }
+        },
+        None => None,
+    };
+
+    let operations = state.operations.clone();
+    let workflow = create_reservation(state, username.clone(), req).map(|resp| {
+        // ответ сохраняется внутри операции, чтобы ключ был записан даже после обрыва соединения
+        if let (Some(claim), Ok(Json(resp))) = (claim, &resp) {
+            claim.complete(resp);
+        }
+        resp.into_response()
+    });
+    operations
+        .run(operation_id, "create_reservation", &username, workflow)
+        .await
+        .map_err(IntoResponse::into_response)
+}
+
+async fn create_reservation(
+    state: AppState,
+    username: String,
+    req: CreateReservationRequest,
+) -> Result<Json<CreateReservationResponse>, ApiError> {
+    let username = username.as_str();
 
-    let client = reqwest::Client::new();
+    let client = &state.client;
     // 1) запросить отель
     let hotel = client
         .get(format!(
             "{}/api/v1/hotel/{}",
-            RESERVATION_ENDPOINT, req.hotel_uid
+            state.config.downstream.reservation, req.hotel_uid
         ))
+        .signed(&state.signer)
+        .traced()
         .send()
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to reservation service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE.into_response()
-        })?
-        .error_for_status()
-        .map_err(|e| {
-            e.status()
-                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_response()
+            ApiError::unavailable("Reservation")
         })?
+        .checked()
+        .await?
         .json::<HotelResponse>()
         .await
         .map_err(|e| {
             log::error!("Failed to parse reservation service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            ApiError::internal()
         })?;
 
     // 2) рассчитать по нему стоимость (end_date - start_date)
@@ -278,19 +672,18 @@ pub async fn post_reservation(
 
     // 3) рассчитать скидку
     let loyalty = client
-        .get(format!("{}/api/v1/loyalty", LOYALTY_ENDPOINT))
+        .get(format!(
+            "{}/api/v1/loyalty",
+            state.config.downstream.loyalty
+        ))
         .header("X-User-Name", username)
+        .signed(&state.signer)
+        .traced()
         .send()
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to loyalty service: {e}");
-            (
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    message: "Loyalty Service unavailable".to_owned(),
-                }),
-            )
-                .into_response()
+            ApiError::unavailable("Loyalty")
         })?;
     let loyalty = match loyalty.status() {
         StatusCode::NOT_FOUND => LoyaltyInfoResponse {
@@ -298,151 +691,171 @@ pub async fn post_reservation(
             discount: Some(5),
             reservation_count: Some(1),
         },
-        StatusCode::OK => LoyaltyInfoResponse::from_json(loyalty)
-            .await
-            .map_err(StatusCode::into_response)?,
+        StatusCode::OK => LoyaltyInfoResponse::from_json(loyalty).await?,
         status => {
             log::error!("unexpected loyalty service response: {status}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+            return Err(ApiError::internal());
         }
     };
 
     let cost = cost - (cost * loyalty.discount.unwrap() / 100);
 
+    let mut saga = state.sagas.begin("create_reservation").await.map_err(|e| {
+        log::error!("Failed to start saga: {e}");
+        ApiError::internal()
+    })?;
+    // Каждый шаг ниже объявляет свою компенсацию до того, как будет выполнен. Если обработчик
+    // вернёт ошибку, сага будет отменена и компенсации выполненных шагов уйдут в очередь
+    // повторной отправки. Идентификаторы оплаты и брони выбираются заранее, чтобы
+    // компенсацию можно было записать в журнал до запроса
+    let payment_uid = Uuid::new_v4();
+    let reservation_uid = Uuid::new_v4();
+
     // 4) запись в payment
-    let payment = client
-        .post(format!("{}/api/v1/payment", PAYMENT_ENDPOINT))
-        .json(&PaymentInfo {
-            status: PaymentStatus::Paid,
-            price: cost as i32,
-        })
-        .send()
-        .await
-        .map_err(|e| {
-            log::error!("Failed to issue request to payment service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE.into_response()
-        })?
-        .error_for_status()
-        .map_err(|e| {
-            e.status()
-                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
-                .into_response()
-        })?
-        .json::<PaymentInfoServiceResponse>()
-        .await
-        .map_err(|e| {
-            log::error!("Failed to parse payment service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR.into_response()
-        })?;
+    let payment = saga
+        .step(
+            "payment",
+            Job::new(
+                JobKind::PaymentCancel,
+                Method::DELETE,
+                Service::Payment,
+                format!("/api/v1/payment/{payment_uid}"),
+            )
+            .header("X-User-Name", username)
+            .ordered_by(username),
+            async {
+                client
+                    .post(format!(
+                        "{}/api/v1/payment",
+                        state.config.downstream.payment
+                    ))
+                    .header("X-User-Name", username)
+                    .json(&PostPaymentServiceRequest {
+                        payment_uid,
+                        status: PaymentStatus::Paid,
+                        price: cost as i32,
+                    })
+                    .signed(&state.signer)
+                    .traced()
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        log::error!("Failed to issue request to payment service: {e}");
+                        ApiError::unavailable("Payment")
+                    })?
+                    .checked()
+                    .await?
+                    .json::<PaymentInfoServiceResponse>()
+                    .await
+                    .map_err(|e| {
+                        log::error!("Failed to parse payment service response: {e}");
+                        ApiError::internal()
+                    })
+            },
+        )
+        .await?;
     log::debug!("Successfully created payment record");
 
-    // 5) запись в loyalty
-    let l = client
-        .put(format!("{}/api/v1/loyalty", LOYALTY_ENDPOINT))
+    // 5) запись в loyalty. Бронь учитывается по идентификатору, поэтому сервис лояльности
+    // знает, было ли увеличение, и компенсация никогда не вычтет лишнего
+    let loyalty_path = format!("/api/v1/loyalty/reservations/{reservation_uid}");
+    saga.step(
+        "loyalty",
+        Job::new(
+            JobKind::LoyaltyDecrement,
+            Method::DELETE,
+            Service::Loyalty,
+            loyalty_path.clone(),
+        )
         .header("X-User-Name", username)
-        .send()
-        .await
-        .map_err(|e| {
-            log::error!("Failed to issue request to loyalty service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE.into_response()
-        })
-        .and_then(|r| {
-            r.error_for_status().map_err(|e| {
-                e.status()
-                    .map(StatusCode::into_response)
-                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())
-            })
-        });
-    log::debug!("Successfully created loyalty record");
-    match l {
-        // 6.1) Сервис доступен, завершаем операцию
-        Ok(_) => {
-            // 6.2) запись в reservation
-            let reservation = client
-                .post(format!("{}/api/v1/reservations", RESERVATION_ENDPOINT))
-                .header("X-User-Name", username)
-                .json(&PostReservationServiceRequest {
-                    hotel_uid: req.hotel_uid,
-                    payment_uid: payment.payment_uid,
-                    start_date: req
-                        .start_date
-                        .and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-                        .and_utc()
-                        .into(),
-                    end_date: req
-                        .end_date
-                        .and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
-                        .and_utc()
-                        .into(),
-                })
-                .send()
-                .await
-                .map_err(|e| {
-                    log::error!("Failed to issue request to reservation service: {e}");
-                    (StatusCode::SERVICE_UNAVAILABLE,).into_response()
-                })?
-                .error_for_status()
-                .map_err(|e| {
-                    e.status()
-                        .map(StatusCode::into_response)
-                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR.into_response())
-                })?
-                .json::<PostReservationServiceResponse>()
-                .await
-                .map_err(|e| {
-                    log::error!("Failed to parse reservation service response: {e}");
-                    (StatusCode::INTERNAL_SERVER_ERROR,).into_response()
-                })?;
-            log::debug!("Successfully created reservation record");
-
-            Ok(Json(CreateReservationResponse {
-                reservation_uid: reservation.reservation_uid,
-                hotel_uid: reservation.hotel_uid,
-                start_date: reservation.start_date.naive_utc().date(),
-                end_date: reservation.end_date.naive_utc().date(),
-                discount: loyalty.discount.unwrap(),
-                status: reservation.status,
-                payment: PaymentInfo {
-                    status: payment.status,
-                    price: payment.price,
-                },
-            }))
-        }
-        // 7.1) Ошибка при обращении в loyalty сервис, откатываем payment
-        Err(_) => {
-            log::warn!("loyalty service unavailable, roll back payment");
+        .ordered_by(username),
+        async {
             client
-                .delete(format!(
-                    "{}/api/v1/payment/{}",
-                    PAYMENT_ENDPOINT, payment.payment_uid
-                ))
+                .put(format!("{}{loyalty_path}", state.config.downstream.loyalty))
+                .header("X-User-Name", username)
+                .signed(&state.signer)
+                .traced()
                 .send()
                 .await
                 .map_err(|e| {
-                    log::error!("Failed to issue request to payment service: {e}");
-                    StatusCode::SERVICE_UNAVAILABLE.into_response()
+                    log::error!("Failed to issue request to loyalty service: {e}");
+                    ApiError::unavailable("Loyalty")
                 })?
-                .error_for_status()
-                .map_err(|e| {
-                    e.status()
-                        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
-                        .into_response()
-                })?
-                .json::<PaymentInfoServiceResponse>()
+                .checked()
                 .await
-                .map_err(|e| {
-                    log::error!("Failed to parse payment service response: {e}");
-                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
-                })?;
-            Err((
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    message: "Loyalty Service unavailable".to_owned(),
-                }),
+        },
+    )
+    .await?;
+    log::debug!("Successfully created loyalty record");
+
+    // 6) запись в reservation
+    let reservation = saga
+        .step(
+            "reservation",
+            Job::new(
+                JobKind::ReservationCancel,
+                Method::DELETE,
+                Service::Reservation,
+                format!("/api/v1/reservations/{reservation_uid}"),
             )
-                .into_response())
-        }
-    }
+            .header("X-User-Name", username)
+            .ordered_by(username),
+            async {
+                client
+                    .post(format!(
+                        "{}/api/v1/reservations",
+                        state.config.downstream.reservation
+                    ))
+                    .header("X-User-Name", username)
+                    .json(&PostReservationServiceRequest {
+                        reservation_uid,
+                        hotel_uid: req.hotel_uid,
+                        payment_uid: payment.payment_uid,
+                        start_date: req
+                            .start_date
+                            .and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+                            .and_utc()
+                            .into(),
+                        end_date: req
+                            .end_date
+                            .and_time(NaiveTime::from_hms_opt(0, 0, 0).unwrap())
+                            .and_utc()
+                            .into(),
+                    })
+                    .signed(&state.signer)
+                    .traced()
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        log::error!("Failed to issue request to reservation service: {e}");
+                        ApiError::unavailable("Reservation")
+                    })?
+                    .checked()
+                    .await?
+                    .json::<PostReservationServiceResponse>()
+                    .await
+                    .map_err(|e| {
+                        log::error!("Failed to parse reservation service response: {e}");
+                        ApiError::internal()
+                    })
+            },
+        )
+        .await?;
+    log::debug!("Successfully created reservation record");
+    saga.complete().await;
+
+    Ok(Json(CreateReservationResponse {
+        reservation_uid: reservation.reservation_uid,
+        hotel_uid: reservation.hotel_uid,
+        start_date: reservation.start_date.naive_utc().date(),
+        end_date: reservation.end_date.naive_utc().date(),
+        discount: loyalty.discount.unwrap(),
+        status: reservation.status,
+        payment: PaymentInfo {
+            status: payment.status,
+            price: payment.price,
+        },
+    }))
 }
 
 #[utoipa::path(
@@ -455,6 +868,7 @@ pub async fn post_reservation(
             body = ReservationResponse,
             content_type = "application/json",
         ),
+        (status = NOT_FOUND, description = "Бронирование не найдено", body = ErrorResponse),
     ),
     params(
         ("X-User-Name", Header, description = "Имя пользователя"),
@@ -462,40 +876,49 @@ pub async fn post_reservation(
     ),
 )]
 pub async fn get_reservation(
+    State(state): State<AppState>,
     Path(reservation_uid): Path<Uuid>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
 
-    let client = reqwest::Client::new();
-    let reservation = client
-        .get(format!(
-            "{RESERVATION_ENDPOINT}/api/v1/reservations/{reservation_uid}"
-        ))
-        .header("X-User-Name", username)
-        .send()
+    let client = &state.client;
+    let reservation = state
+        .breakers
+        .reservation
+        .send(
+            client
+                .get(format!(
+                    "{}/api/v1/reservations/{reservation_uid}",
+                    state.config.downstream.reservation
+                ))
+                .header("X-User-Name", username),
+        )
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to reservation service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
+            ApiError::unavailable("Reservation")
         })?
+        .checked()
+        .await?
         .json::<ReservationServiceResponse>()
         .await
         .map_err(|e| {
             log::error!("Failed to parse reservation service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            ApiError::internal()
         })?;
 
-    let payment = client
-        .get(format!(
-            "{}/api/v1/payment/{}",
-            PAYMENT_ENDPOINT, reservation.payment_uid
-        ))
-        .send()
+    let payment = state
+        .breakers
+        .payment
+        .send(
+            client
+                .get(format!(
+                    "{}/api/v1/payment/{}",
+                    state.config.downstream.payment, reservation.payment_uid
+                ))
+                .header("X-User-Name", username),
+        )
         .await;
     let payment = match payment {
         Err(e) => {
@@ -504,6 +927,9 @@ pub async fn get_reservation(
         }
         Ok(p) => PaymentInfo::try_from_json(p).await,
     };
+    if payment.is_none() {
+        metrics().fallback("/api/v1/reservations/{reservationUid}", "payment");
+    }
 
     Ok(Json(ReservationResponse::from_svc_responses(
         reservation,
@@ -520,165 +946,310 @@ pub async fn get_reservation(
             description = "Бронирование отменено",
             content_type = "application/json",
         ),
+        (
+            status = ACCEPTED,
+            description = "Бронирование отменено, отмена оплаты или баллов лояльности поставлена в очередь",
+            body = CancelReservationResponse,
+            content_type = "application/json",
+        ),
+        (status = NOT_FOUND, description = "Бронирование не найдено", body = ErrorResponse),
+        (status = CONFLICT, description = "Операция с таким идентификатором уже есть", body = ErrorResponse),
+        (status = SERVICE_UNAVAILABLE, description = "Очередь повторных запросов заполнена, бронирование не изменено", body = ErrorResponse),
     ),
     params(
         ("X-User-Name", Header, description = "Имя пользователя"),
         ("reservationUid", Path, description = "Идентификатор запрашиваемой брони"),
+        (
+            "X-Operation-Id" = Option<Uuid>,
+            Header,
+            description = "Идентификатор операции, выбранный клиентом: по нему можно узнать результат, если ответ потерян"
+        ),
     ),
 )]
 pub async fn delete_reservation(
     Path(reservation_uid): Path<Uuid>,
     headers: HeaderMap,
     State(state): State<AppState>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?.to_owned();
+    let operation_id = operation_id(&headers)?;
+
+    let operations = state.operations.clone();
+    let workflow = cancel_reservation(state, username.clone(), reservation_uid);
+    operations
+        .run(
+            operation_id,
+            "cancel_reservation",
+            &username,
+            workflow.map(IntoResponse::into_response),
+        )
+        .await
+}
+
+async fn cancel_reservation(
+    state: AppState,
+    username: String,
+    reservation_uid: Uuid,
+) -> Result<Response, ApiError> {
+    let username = username.as_str();
 
-    let client = reqwest::Client::new();
+    let client = &state.client;
     let reservation = client
         .get(format!(
             "{}/api/v1/reservations/{}",
-            RESERVATION_ENDPOINT, reservation_uid
+            state.config.downstream.reservation, reservation_uid
         ))
         .header("X-User-Name", username)
+        .signed(&state.signer)
+        .traced()
         .send()
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to reservation service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
+            ApiError::unavailable("Reservation")
         })?
-        .error_for_status()
-        .map_err(|e| e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))?
+        .checked()
+        .await?
         .json::<ReservationServiceResponse>()
         .await
         .map_err(|e| {
             log::error!("Failed to parse reservation service response: {e}");
-            StatusCode::INTERNAL_SERVER_ERROR
+            ApiError::internal()
         })?;
 
+    // Оплата, которую сервис оплаты отказывается отменять, проверяется до отмены брони,
+    // чтобы не оставить отменённую бронь с действующей оплатой. Если сервис недоступен,
+    // отмена оплаты будет поставлена в очередь
+    let payment_path = format!("/api/v1/payment/{}", reservation.payment_uid);
+    let payment_check = client
+        .get(format!(
+            "{}{}",
+            state.config.downstream.payment, payment_path
+        ))
+        .header("X-User-Name", username)
+        .signed(&state.signer)
+        .traced()
+        .send()
+        .await;
+    match payment_check {
+        Ok(r) if r.status().is_client_error() => {
+            return Err(ApiError::from_downstream(r).await);
+        }
+        Ok(r) if !r.status().is_success() => {
+            log::warn!("Payment service responded with {}", r.status())
+        }
+        Err(e) => log::warn!("Failed to issue request to payment service: {e}"),
+        Ok(_) => {}
+    }
+
+    // Место в очереди под отмену оплаты и списание баллов занимается до отмены брони,
+    // чтобы переполненная очередь не оставила отмену наполовину выполненной
+    let mut slots = state.queue.reserve(2).map_err(|e| {
+        log::error!("Failed to reserve retry queue space: {e}");
+        ApiError::from(e)
+    })?;
+
     client
         .delete(format!(
             "{}/api/v1/reservations/{}",
-            RESERVATION_ENDPOINT, reservation_uid
+            state.config.downstream.reservation, reservation_uid
         ))
         .header("X-User-Name", username)
+        .signed(&state.signer)
+        .traced()
         .send()
         .await
         .map_err(|e| {
             log::error!("Failed to issue request to reservation service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
+            ApiError::unavailable("Reservation")
         })?
-        .error_for_status()
-        .map_err(|e| e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))?;
+        .checked()
+        .await?;
 
-    client
+    // Бронь уже отменена, поэтому оплату нужно отменить в любом случае: если запрос не
+    // удался, он уходит в очередь и отмена считается незавершённой
+    let mut pending = Vec::new();
+    let payment_resp = client
         .delete(format!(
-            "{}/api/v1/payment/{}",
-            PAYMENT_ENDPOINT, reservation.payment_uid
+            "{}{}",
+            state.config.downstream.payment, payment_path
         ))
         .header("X-User-Name", username)
+        .signed(&state.signer)
+        .traced()
         .send()
-        .await
-        .map_err(|e| {
-            log::error!("Failed to issue request to payment service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
-        })?
-        .error_for_status()
-        .map_err(|e| e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))?;
+        .await;
+    match payment_resp {
+        Ok(r) if r.status().is_success() => {}
+        r => {
+            match r {
+                Ok(r) => log::error!("Payment service responded with {}", r.status()),
+                Err(e) => log::error!("Failed to issue request to payment service: {e}"),
+            }
+            let job = Job::new(
+                JobKind::PaymentCancel,
+                Method::DELETE,
+                Service::Payment,
+                payment_path,
+            )
+            .header("X-User-Name", username)
+            .ordered_by(username);
+            slots.push(job).map_err(|e| {
+                log::error!("Failed to queue payment request: {e}");
+                ApiError::from(e)
+            })?;
+            pending.push(Service::Payment);
+        }
+    }
 
     let loyalty_resp = client
-        .delete(format!("{}/api/v1/loyalty", LOYALTY_ENDPOINT))
+        .delete(format!(
+            "{}/api/v1/loyalty",
+            state.config.downstream.loyalty
+        ))
         .header("X-User-Name", username)
+        .signed(&state.signer)
+        .traced()
         .send()
-        .await
-        .map_err(|e| {
-            log::error!("Failed to issue request to loyalty service: {e}");
-            StatusCode::SERVICE_UNAVAILABLE
-        })
-        .and_then(|s| {
-            s.error_for_status()
-                .map_err(|e| e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-        });
-
-    if let Err(e) = loyalty_resp {
-        log::debug!("Loyalty service unavailable ({e}), request is being put into send queue");
-        let username = username.to_owned();
-        let resend_lambda = Box::new(move || -> RequestReturnValue {
-            let username = username.clone();
-            Box::pin(async move {
-                reqwest::Client::new()
-                    .delete(format!("{}/api/v1/loyalty", LOYALTY_ENDPOINT))
-                    .header("X-User-Name", username)
-                    .send()
-                    .await
-                    .map_err(|e| {
-                        log::error!("Failed to issue request to loyalty service: {e}");
-                        StatusCode::SERVICE_UNAVAILABLE
-                    })
-                    .and_then(|s| {
-                        s.error_for_status()
-                            .map_err(|e| e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-                    })
-                    .map(|_| ())
-            })
-        });
-        state
-            .msg_chan
-            .send(Message {
-                timeout: Utc::now() + Duration::seconds(10),
-                request: resend_lambda,
-            })
-            .await
-            .expect("Failed to add message to the queue");
+        .await;
+    match loyalty_resp {
+        Ok(r) if r.status().is_success() => {}
+        r => {
+            match r {
+                Ok(r) => log::error!("Loyalty service responded with {}", r.status()),
+                Err(e) => log::error!("Failed to issue request to loyalty service: {e}"),
+            }
+            let job = Job::new(
+                JobKind::LoyaltyDecrement,
+                Method::DELETE,
+                Service::Loyalty,
+                "/api/v1/loyalty".to_owned(),
+            )
+            .header("X-User-Name", username)
+            .ordered_by(username);
+            slots.push(job).map_err(|e| {
+                log::error!("Failed to queue loyalty request: {e}");
+                ApiError::from(e)
+            })?;
+            pending.push(Service::Loyalty);
+        }
     }
 
-    Ok(StatusCode::NO_CONTENT)
+    if pending.is_empty() {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(CancelReservationResponse {
+            reservation_uid,
+            pending,
+        }),
+    )
+        .into_response())
 }
 
 #[utoipa::path(
     get,
     path = "/api/v1/loyalty",
     responses(
-        (status = OK, body = LoyaltyInfoResponse, description = "Данные о бонусном счёте")
+        (status = OK, body = LoyaltyInfoResponse, description = "Данные о бонусном счёте"),
+        (status = SERVICE_UNAVAILABLE, description = "Сервис лояльности недоступен", body = ErrorResponse),
     ),
     params(
         ("X-User-Name", Header, description="Имя пользователя, для которого будет заведена бронь")
     ),
 )]
-pub async fn get_loyalty(headers: HeaderMap) -> Result<impl IntoResponse, impl IntoResponse> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(ErrorResponse::resp_from_status(StatusCode::BAD_REQUEST))?
-        .to_str()
-        .map_err(|_| ErrorResponse::resp_from_status(StatusCode::BAD_REQUEST))?;
+pub async fn get_loyalty(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
 
-    let resp = reqwest::Client::new()
-        .get(format!("{LOYALTY_ENDPOINT}/api/v1/loyalty"))
-        .header("X-User-Name", username)
-        .send()
+    let resp = state
+        .breakers
+        .loyalty
+        .send(
+            state
+                .client
+                .get(format!(
+                    "{}/api/v1/loyalty",
+                    state.config.downstream.loyalty
+                ))
+                .header("X-User-Name", username),
+        )
         .await
         .map_err(|e| {
-            log::error!("Failed to issue request to reservation service: {e}");
-            (
-                StatusCode::SERVICE_UNAVAILABLE,
-                Json(ErrorResponse {
-                    message: "Loyalty Service unavailable".to_owned(),
-                }),
-            )
-        })?
-        .error_for_status()
-        .map_err(|e| {
-            ErrorResponse::resp_from_status(e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+            log::error!("Failed to issue request to loyalty service: {e}");
+            ApiError::unavailable("Loyalty")
         })?
+        .checked()
+        .await?
         .json::<LoyaltyInfoResponse>()
         .await
         .map_err(|e| {
-            log::error!("Failed to parse reservation service response: {e}");
-            ErrorResponse::resp_from_status(StatusCode::INTERNAL_SERVER_ERROR)
+            log::error!("Failed to parse loyalty service response: {e}");
+            ApiError::internal()
         })?;
 
-    Ok::<_, (StatusCode, Json<ErrorResponse>)>(Json(resp))
+    Ok(Json(resp))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/operations",
+    responses(
+        (
+            status = OK,
+            description = "Операции пользователя, начиная с последней",
+            body = Vec<Operation>,
+            content_type = "application/json",
+        ),
+    ),
+    params(
+        ("X-User-Name", Header, description = "Имя пользователя"),
+    ),
+)]
+pub async fn get_operations(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+
+    Ok::<_, ApiError>(Json(state.operations.list(username)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/operations/{operationId}",
+    responses(
+        (
+            status = OK,
+            description = "Состояние операции",
+            body = Operation,
+            content_type = "application/json",
+        ),
+        (
+            status = NOT_FOUND,
+            description = "Операция не найдена",
+            body = ErrorResponse,
+            content_type = "application/json",
+        ),
+    ),
+    params(
+        ("X-User-Name", Header, description = "Имя пользователя"),
+        ("operationId", Path, description = "Идентификатор операции из заголовка X-Operation-Id запроса или ответа"),
+    ),
+)]
+pub async fn get_operation(
+    Path(operation_id): Path<Uuid>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+
+    state
+        .operations
+        .get(operation_id, username)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(format!("Operation {operation_id} not found")))
 }
@@ -0,0 +1,407 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    error::ApiError,
+    journal::Journal,
+    metrics::metrics,
+    queue::{Job, RetryQueue},
+};
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum SagaEntry {
+    Started {
+        id: Uuid,
+        name: String,
+    },
+    /// Written before the step runs, with the job that undoes it
+    Step {
+        id: Uuid,
+        step: String,
+        compensation: Option<Job>,
+    },
+    Done {
+        id: Uuid,
+        step: String,
+    },
+    /// The step was rejected, so it did not happen and needs no compensation
+    Failed {
+        id: Uuid,
+        step: String,
+    },
+    /// Compensations that were queued before queueing the rest failed
+    Queued {
+        id: Uuid,
+        jobs: Vec<Uuid>,
+    },
+    Completed {
+        id: Uuid,
+    },
+    Aborted {
+        id: Uuid,
+    },
+}
+
+impl SagaEntry {
+    fn id(&self) -> Uuid {
+        match self {
+            Self::Started { id, .. }
+            | Self::Step { id, .. }
+            | Self::Done { id, .. }
+            | Self::Failed { id, .. }
+            | Self::Queued { id, .. }
+            | Self::Completed { id }
+            | Self::Aborted { id } => *id,
+        }
+    }
+}
+
+/// Saga that neither completed nor was compensated before the last shutdown
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnfinishedSaga {
+    pub id: Uuid,
+    pub name: String,
+    /// Compensations of steps that were started. Those of steps that were not confirmed
+    /// may find nothing to undo
+    pub compensations: Vec<Job>,
+}
+
+/// Step of an unfinished saga read from the journal
+struct JournaledStep {
+    step: String,
+    compensation: Job,
+    done: bool,
+}
+
+#[derive(Debug)]
+struct SagasInner {
+    journal: Journal,
+    queue: RetryQueue,
+    /// Sagas that are neither completed nor compensated, only their entries survive compaction
+    running: Mutex<HashSet<Uuid>>,
+}
+
+/// Runs multi-service workflows as sagas. Progress of each saga is journaled to disk and
+/// compensations of completed steps are delivered through the retry queue
+#[derive(Debug, Clone)]
+pub struct Sagas {
+    inner: Arc<SagasInner>,
+}
+
+impl Sagas {
+    /// Opens the saga journal, returning sagas that were interrupted by the last shutdown
+    pub fn open(
+        path: impl AsRef<Path>,
+        queue: RetryQueue,
+    ) -> io::Result<(Self, Vec<UnfinishedSaga>)> {
+        let (journal, entries) = Journal::open(path)?;
+
+        let mut unfinished: Vec<(UnfinishedSaga, Vec<JournaledStep>)> = Vec::new();
+        let mut kept = Vec::new();
+        for entry in entries {
+            match &entry {
+                SagaEntry::Started { id, name } => unfinished.push((
+                    UnfinishedSaga {
+                        id: *id,
+                        name: name.clone(),
+                        compensations: Vec::new(),
+                    },
+                    Vec::new(),
+                )),
+                SagaEntry::Step {
+                    id,
+                    step,
+                    compensation: Some(job),
+                } => {
+                    if let Some((_, steps)) = unfinished.iter_mut().find(|(s, _)| s.id == *id) {
+                        steps.push(JournaledStep {
+                            step: step.clone(),
+                            compensation: job.clone(),
+                            done: false,
+                        });
+                    }
+                }
+                SagaEntry::Step { .. } => {}
+                SagaEntry::Done { id, step } => {
+                    if let Some((_, steps)) = unfinished.iter_mut().find(|(s, _)| s.id == *id) {
+                        for s in steps.iter_mut().filter(|s| s.step == *step) {
+                            s.done = true;
+                        }
+                    }
+                }
+                SagaEntry::Failed { id, step } => {
+                    if let Some((_, steps)) = unfinished.iter_mut().find(|(s, _)| s.id == *id) {
+                        steps.retain(|s| s.step != *step);
+                    }
+                }
+                SagaEntry::Queued { id, jobs } => {
+                    if let Some((_, steps)) = unfinished.iter_mut().find(|(s, _)| s.id == *id) {
+                        steps.retain(|s| !jobs.contains(&s.compensation.id));
+                    }
+                }
+                SagaEntry::Completed { id } | SagaEntry::Aborted { id } => {
+                    unfinished.retain(|(s, _)| s.id != *id);
+                }
+            }
+            kept.push(entry);
+        }
+        let running: HashSet<Uuid> = unfinished.iter().map(|(s, _)| s.id).collect();
+        kept.retain(|e| running.contains(&e.id()));
+        journal.rewrite(kept)?;
+
+        let unfinished = unfinished
+            .into_iter()
+            .map(|(mut saga, steps)| {
+                for step in steps {
+                    if step.done {
+                        saga.compensations.push(step.compensation);
+                        continue;
+                    }
+                    // the process stopped while the step was running, it may have happened
+                    log::warn!(
+                        "Step {} of saga {} ({}) was not confirmed",
+                        step.step,
+                        saga.name,
+                        saga.id
+                    );
+                    saga.compensations.push(step.compensation.allow_missing());
+                }
+                saga
+            })
+            .collect();
+
+        Ok((
+            Self {
+                inner: Arc::new(SagasInner {
+                    journal,
+                    queue,
+                    running: Mutex::new(running),
+                }),
+            },
+            unfinished,
+        ))
+    }
+
+    pub async fn begin(&self, name: &str) -> io::Result<Saga> {
+        let id = Uuid::new_v4();
+        self.inner.running.lock().unwrap().insert(id);
+        let entry = SagaEntry::Started {
+            id,
+            name: name.to_owned(),
+        };
+        let inner = self.inner.clone();
+        let started = tokio::task::spawn_blocking(move || inner.journal.append(&entry))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|r| r);
+        if let Err(e) = started {
+            self.inner.running.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        log::debug!("Started saga {name} ({id})");
+
+        Ok(Saga {
+            id,
+            name: name.to_owned(),
+            sagas: self.clone(),
+            compensations: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// Compensates sagas that were interrupted by a restart
+    pub fn recover(&self, unfinished: Vec<UnfinishedSaga>) {
+        for saga in unfinished {
+            log::warn!("Compensating interrupted saga {} ({})", saga.name, saga.id);
+            self.compensate(saga.id, &saga.name, saga.compensations);
+        }
+    }
+
+    /// Queues all compensations or none of them. A saga that could not be compensated stays
+    /// in the journal and is compensated again after a restart. Only sagas that had something
+    /// left to queue are counted as compensated
+    fn compensate(&self, id: Uuid, name: &str, compensations: Vec<Job>) {
+        // the jobs may have been queued before a crash
+        let jobs: Vec<Job> = compensations
+            .into_iter()
+            .rev()
+            .filter(|job| !self.inner.queue.contains(job.id))
+            .collect();
+        let mut slots = match self.inner.queue.reserve(jobs.len()) {
+            Ok(slots) => slots,
+            Err(e) => {
+                log::error!("Failed to queue compensations for saga {id}: {e}");
+                return;
+            }
+        };
+
+        let mut queued = Vec::new();
+        for job in jobs {
+            let job_id = job.id;
+            if let Err(e) = slots.push(job) {
+                log::error!("Failed to queue compensation for saga {id}: {e}");
+                // queued jobs may be delivered before the restart, they must not be repeated
+                if !queued.is_empty() {
+                    self.append(&SagaEntry::Queued { id, jobs: queued });
+                }
+                return;
+            }
+            queued.push(job_id);
+        }
+        if !queued.is_empty() {
+            metrics().compensation(name);
+        }
+        self.append(&SagaEntry::Aborted { id });
+        self.inner.running.lock().unwrap().remove(&id);
+    }
+
+    fn append(&self, entry: &SagaEntry) {
+        if let Err(e) = self.inner.journal.append(entry) {
+            log::error!("Failed to write saga journal: {e}");
+        }
+    }
+
+    /// Appends on the blocking pool, so waiting for the disk does not stall the runtime
+    async fn append_blocking(&self, entry: SagaEntry) {
+        let sagas = self.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || sagas.append(&entry)).await {
+            log::error!("Failed to write saga journal: {e}");
+        }
+    }
+
+    /// Drops entries of finished sagas from the journal. Returns the number of dropped entries
+    pub fn compact(&self) -> io::Result<usize> {
+        // appends wait for the compaction, so a saga started meanwhile is already running
+        self.inner
+            .journal
+            .retain(|e: &SagaEntry| self.inner.running.lock().unwrap().contains(&e.id()))
+    }
+
+    /// Compacts the journal every `interval`, so it does not grow until the next restart
+    pub async fn compact_every(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let sagas = self.clone();
+            match tokio::task::spawn_blocking(move || sagas.compact()).await {
+                Ok(Ok(dropped)) => log::debug!("Dropped {dropped} entries of finished sagas"),
+                Ok(Err(e)) => log::error!("Failed to compact saga journal: {e}"),
+                Err(e) => log::error!("Failed to compact saga journal: {e}"),
+            }
+        }
+    }
+}
+
+/// Error of a saga step
+pub trait StepError {
+    /// The service refused the request, so the step surely did not happen. After any other
+    /// error the step may have taken effect and is compensated
+    fn rejected(&self) -> bool;
+}
+
+impl StepError for ApiError {
+    fn rejected(&self) -> bool {
+        self.status.is_client_error() && self.status != StatusCode::REQUEST_TIMEOUT
+    }
+}
+
+/// A running saga. Dropping it before `complete` compensates every step that succeeded,
+/// in reverse order. A restart compensates steps that were running as well
+#[derive(Debug)]
+pub struct Saga {
+    id: Uuid,
+    name: String,
+    sagas: Sagas,
+    compensations: Vec<Job>,
+    finished: bool,
+}
+
+impl Saga {
+    /// Journals `compensation`, the job that undoes the step, then runs `action`. The step
+    /// is compensated unless the action is rejected. After a timeout, a transport error or
+    /// a 5xx the step may have happened, so its compensation is kept and may find nothing
+    /// to undo
+    pub async fn step<T, E: StepError>(
+        &mut self,
+        step: &str,
+        compensation: Job,
+        action: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        self.journal(SagaEntry::Step {
+            id: self.id,
+            step: step.to_owned(),
+            compensation: Some(compensation.clone()),
+        })
+        .await;
+        let out = match action.await {
+            Ok(out) => out,
+            Err(e) if e.rejected() => {
+                self.journal(SagaEntry::Failed {
+                    id: self.id,
+                    step: step.to_owned(),
+                })
+                .await;
+                return Err(e);
+            }
+            Err(e) => {
+                log::warn!(
+                    "Step {step} of saga {} ({}) failed with an unknown outcome",
+                    self.name,
+                    self.id
+                );
+                self.compensations.push(compensation.allow_missing());
+                return Err(e);
+            }
+        };
+
+        self.journal(SagaEntry::Done {
+            id: self.id,
+            step: step.to_owned(),
+        })
+        .await;
+        log::debug!("Saga {} ({}) finished step {step}", self.name, self.id);
+        self.compensations.push(compensation);
+
+        Ok(out)
+    }
+
+    /// A journal failure does not stop the saga, it only costs crash recovery
+    async fn journal(&self, entry: SagaEntry) {
+        self.sagas.append_blocking(entry).await;
+    }
+
+    pub async fn complete(mut self) {
+        self.finished = true;
+        self.journal(SagaEntry::Completed { id: self.id }).await;
+        self.sagas.inner.running.lock().unwrap().remove(&self.id);
+        log::debug!("Saga {} ({}) completed", self.name, self.id);
+    }
+}
+
+impl Drop for Saga {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        log::warn!(
+            "Saga {} ({}) failed, compensating {} steps",
+            self.name,
+            self.id,
+            self.compensations.len()
+        );
+        self.sagas
+            .compensate(self.id, &self.name, std::mem::take(&mut self.compensations));
+    }
+}
@@ -0,0 +1,123 @@
+use std::{fmt::Debug, sync::Arc};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    RequestBuilder,
+};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub const SIGNATURE: &str = "X-Signature";
+pub const TIMESTAMP: &str = "X-Signature-Timestamp";
+pub const NONCE: &str = "X-Signature-Nonce";
+
+/// Headers the backends authorize requests by, their values are signed too
+pub const SIGNED_HEADERS: [&str; 1] = ["X-User-Name"];
+
+/// What is signed: method, path with query, signed headers, unix timestamp, nonce and body
+/// hash. Backends build the same string to check the signature
+pub fn payload(
+    method: &str,
+    path: &str,
+    headers: &HeaderMap,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    let signed: String = SIGNED_HEADERS
+        .iter()
+        .map(|name| {
+            let value = headers.get(*name).map_or(&b""[..], |v| v.as_bytes());
+            format!(
+                "{}:{}\n",
+                name.to_lowercase(),
+                String::from_utf8_lossy(value)
+            )
+        })
+        .collect();
+    format!(
+        "{method}\n{path}\n{signed}{timestamp}\n{nonce}\n{}",
+        hex::encode(Sha256::digest(body))
+    )
+}
+
+/// Signs requests to the backends with HMAC-SHA256 over a secret shared with them. Without
+/// a secret requests are sent unsigned
+#[derive(Clone, Default)]
+pub struct Signer {
+    secret: Option<Arc<[u8]>>,
+}
+
+impl Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Signer")
+            .field("enabled", &self.secret.is_some())
+            .finish()
+    }
+}
+
+impl Signer {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            secret: (!secret.is_empty()).then(|| Arc::from(secret.as_bytes())),
+        }
+    }
+
+    pub fn sign(&self, req: &mut reqwest::Request) {
+        let Some(secret) = &self.secret else {
+            return;
+        };
+        let url = req.url();
+        let path = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_owned(),
+        };
+        let timestamp = Utc::now().timestamp();
+        let nonce = Uuid::new_v4().to_string();
+        let body = req.body().and_then(|b| b.as_bytes()).unwrap_or_default();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        let payload = payload(
+            req.method().as_str(),
+            &path,
+            req.headers(),
+            timestamp,
+            &nonce,
+            body,
+        );
+        mac.update(payload.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        let headers = req.headers_mut();
+        headers.insert(SIGNATURE, HeaderValue::from_str(&signature).unwrap());
+        headers.insert(TIMESTAMP, HeaderValue::from(timestamp));
+        headers.insert(NONCE, HeaderValue::from_str(&nonce).unwrap());
+    }
+}
+
+pub trait SignRequest {
+    /// Signs the request as it is built so far, so the body must already be set
+    fn signed(self, signer: &Signer) -> Self;
+}
+
+impl SignRequest for RequestBuilder {
+    fn signed(self, signer: &Signer) -> Self {
+        if signer.secret.is_none() {
+            return self;
+        }
+        // streamed bodies can't be cloned, hashed or signed
+        let Some(builder) = self.try_clone() else {
+            return self;
+        };
+        match builder.build_split() {
+            (client, Ok(mut req)) => {
+                signer.sign(&mut req);
+                RequestBuilder::from_parts(client, req)
+            }
+            // the original builder fails with the same error when sent
+            (_, Err(_)) => self,
+        }
+    }
+}
@@ -1,2 +1,1986 @@
+use std::time::Duration;
+
+use axum::{http::StatusCode, response::IntoResponse};
+use chrono::Utc;
+use reqwest::Method;
+use uuid::Uuid;
+
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitState},
+    config::{Args, Config, ConfigError, LogFormat, QueueConfig},
+    error::ApiError,
+    idempotency::{Claim, IdempotencyError, IdempotencyStore},
+    operations::{OperationStatus, Operations},
+    queue::{
+        DeadLetter, DeadLetterStore, Job, JobKind, JobLog, QueueError, RetryPolicy, RetryQueue,
+        Service,
+    },
+    saga::Sagas,
+    signature::Signer,
+};
+
 #[test]
 fn hello_world() {}
+
+/// The global logger can only be installed once per process, so tests share it
+fn logging() -> crate::logger::Logging {
+    static LOGGING: std::sync::OnceLock<crate::logger::Logging> = std::sync::OnceLock::new();
+    LOGGING
+        .get_or_init(|| {
+            let mut config = crate::config::Config::default().log;
+            config.level = "off".to_owned();
+            crate::logger::init(&config)
+        })
+        .clone()
+}
+
+fn breaker(cooldown: Duration) -> CircuitBreaker {
+    CircuitBreaker::new(
+        "test",
+        CircuitBreakerConfig {
+            failure_threshold: 3,
+            window: Duration::from_secs(60),
+            cooldown,
+        },
+    )
+}
+
+#[test]
+fn circuit_opens_after_threshold() {
+    let b = breaker(Duration::from_secs(60));
+    b.on_failure("boom");
+    b.on_failure("boom");
+    assert!(b.try_acquire());
+
+    b.on_failure("boom");
+    assert!(!b.try_acquire());
+}
+
+#[test]
+fn circuit_success_resets_failures() {
+    let b = breaker(Duration::from_secs(60));
+    b.on_failure("boom");
+    b.on_failure("boom");
+    b.on_success();
+    b.on_failure("boom");
+    b.on_failure("boom");
+    assert!(b.try_acquire());
+}
+
+#[test]
+fn circuit_half_open_lets_single_probe() {
+    let b = breaker(Duration::ZERO);
+    for _ in 0..3 {
+        b.on_failure("boom");
+    }
+
+    assert!(b.try_acquire());
+    assert!(!b.try_acquire());
+
+    b.on_success();
+    assert!(b.try_acquire());
+    assert!(b.try_acquire());
+}
+
+#[test]
+fn circuit_failed_probe_reopens() {
+    let b = breaker(Duration::from_millis(20));
+    for _ in 0..3 {
+        b.on_failure("boom");
+    }
+    std::thread::sleep(Duration::from_millis(30));
+
+    assert!(b.try_acquire());
+    b.on_failure("boom");
+    assert!(!b.try_acquire());
+}
+
+#[test]
+fn circuit_forced_open_until_reset() {
+    let b = breaker(Duration::ZERO);
+    b.force_open();
+    assert!(!b.try_acquire());
+    b.on_success();
+    assert!(!b.try_acquire());
+
+    b.reset();
+    assert!(b.try_acquire());
+}
+
+#[test]
+fn circuit_snapshot_reports_failures() {
+    let b = breaker(Duration::from_secs(60));
+    b.on_failure("connection refused");
+    b.on_failure("connection refused");
+    b.on_failure("timed out");
+
+    let snapshot = b.snapshot();
+    assert_eq!(snapshot.state, CircuitState::Open);
+    assert_eq!(snapshot.recent_failures, 3);
+    assert_eq!(snapshot.last_error.as_deref(), Some("timed out"));
+    assert!(snapshot.next_probe_in_ms.is_some());
+}
+
+fn loyalty_job() -> Job {
+    Job::new(
+        JobKind::LoyaltyDecrement,
+        Method::DELETE,
+        Service::Loyalty,
+        "/api/v1/loyalty".to_owned(),
+    )
+    .header("X-User-Name", "Test Max")
+}
+
+#[test]
+fn job_log_replays_pending_jobs() {
+    let path = std::env::temp_dir().join(format!("gateway-queue-{}.jsonl", Uuid::new_v4()));
+    let delivered = loyalty_job();
+    let pending = loyalty_job();
+    {
+        let (log, replayed) = JobLog::open(&path).unwrap();
+        assert!(replayed.is_empty());
+        log.enqueued(&delivered).unwrap();
+        log.enqueued(&pending).unwrap();
+        log.done(delivered.id).unwrap();
+    }
+
+    let (_, replayed) = JobLog::open(&path).unwrap();
+    assert_eq!(replayed, vec![pending.clone()]);
+
+    // compacted log still holds the pending job
+    let (_, replayed) = JobLog::open(&path).unwrap();
+    assert_eq!(replayed, vec![pending]);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn job_log_skips_torn_entries() {
+    let path = std::env::temp_dir().join(format!("gateway-queue-{}.jsonl", Uuid::new_v4()));
+    let job = loyalty_job();
+    {
+        let (log, _) = JobLog::open(&path).unwrap();
+        log.enqueued(&job).unwrap();
+    }
+    let mut contents = std::fs::read_to_string(&path).unwrap();
+    contents.push_str("{\"op\":\"enq");
+    std::fs::write(&path, contents).unwrap();
+
+    let (_, replayed) = JobLog::open(&path).unwrap();
+    assert_eq!(replayed, vec![job]);
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn retry_backoff_grows_and_caps() {
+    let policy = RetryPolicy {
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_secs(1),
+        multiplier: 2.0,
+        jitter: 0.0,
+        max_attempts: 10,
+        max_age: Duration::from_secs(60),
+    };
+    assert_eq!(policy.backoff(1), Duration::from_millis(100));
+    assert_eq!(policy.backoff(2), Duration::from_millis(200));
+    assert_eq!(policy.backoff(4), Duration::from_millis(800));
+    assert_eq!(policy.backoff(10), Duration::from_secs(1));
+}
+
+#[test]
+fn retry_backoff_jitter_stays_in_bounds() {
+    let policy = RetryPolicy {
+        initial_backoff: Duration::from_secs(1),
+        max_backoff: Duration::from_secs(1),
+        multiplier: 2.0,
+        jitter: 0.2,
+        max_attempts: 10,
+        max_age: Duration::from_secs(60),
+    };
+    for _ in 0..100 {
+        let d = policy.backoff(3);
+        assert!(d >= Duration::from_millis(800) && d <= Duration::from_millis(1200));
+    }
+}
+
+#[test]
+fn dead_letters_survive_reopen() {
+    let path = std::env::temp_dir().join(format!("gateway-dead-{}.json", Uuid::new_v4()));
+    let job = loyalty_job();
+    {
+        let store = DeadLetterStore::open(&path).unwrap();
+        store
+            .add(DeadLetter {
+                job: job.clone(),
+                reason: "expired".to_owned(),
+                failed_at: Utc::now(),
+            })
+            .unwrap();
+    }
+
+    let store = DeadLetterStore::open(&path).unwrap();
+    assert_eq!(store.list().len(), 1);
+    let letter = store.take(job.id).unwrap().unwrap();
+    assert_eq!(letter.job, job);
+    assert!(store.take(job.id).unwrap().is_none());
+    assert!(DeadLetterStore::open(&path).unwrap().list().is_empty());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn full_queue_rejects_jobs() {
+    let dir = std::env::temp_dir().join(format!("gateway-queue-{}", Uuid::new_v4()));
+    let (log, _) = JobLog::open(dir.join("queue.jsonl")).unwrap();
+    let dead_letters = DeadLetterStore::open(dir.join("dead_letters.json")).unwrap();
+    let queue = RetryQueue::new(
+        log,
+        dead_letters,
+        &QueueConfig {
+            size: 0,
+            workers: 1,
+        },
+        Config::default().retry,
+        reqwest::Client::new(),
+        Config::default().downstream,
+        Signer::default(),
+    );
+
+    assert!(matches!(queue.push(loyalty_job()), Err(QueueError::Full)));
+    assert_eq!(queue.len(), 0);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn reserved_queue_space_counts_against_capacity() {
+    let dir = std::env::temp_dir().join(format!("gateway-queue-{}", Uuid::new_v4()));
+    let queue = saga_queue(&dir, 3);
+
+    let mut slots = queue.reserve(2).unwrap();
+    assert!(matches!(queue.reserve(2), Err(QueueError::Full)));
+    queue.push(loyalty_job()).unwrap();
+    assert!(matches!(queue.push(loyalty_job()), Err(QueueError::Full)));
+
+    // reserved places are still available to their owner
+    slots.push(loyalty_job()).unwrap();
+    assert_eq!(queue.len(), 2);
+    // the unused place is given back
+    drop(slots);
+    queue.push(loyalty_job()).unwrap();
+    assert_eq!(queue.len(), 3);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn jobs_of_one_user_are_delivered_in_order() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::Path, routing::post, Router};
+
+    let attempts = Arc::new(Mutex::new(Vec::new()));
+    let backend = Router::new().route(
+        "/api/v1/loyalty/{n}",
+        post({
+            let attempts = attempts.clone();
+            move |Path(n): Path<u32>| async move {
+                let mut attempts = attempts.lock().unwrap();
+                attempts.push(n);
+                // the first job fails once, the rest of the lane has to wait for it
+                if attempts.len() == 1 {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::OK
+                }
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let dir = std::env::temp_dir().join(format!("gateway-queue-{}", Uuid::new_v4()));
+    let (log, _) = JobLog::open(dir.join("queue.jsonl")).unwrap();
+    let dead_letters = DeadLetterStore::open(dir.join("dead_letters.json")).unwrap();
+    let mut downstream = Config::default().downstream;
+    downstream.loyalty = backend_url;
+    let queue = RetryQueue::new(
+        log,
+        dead_letters,
+        &QueueConfig {
+            size: 10,
+            workers: 4,
+        },
+        Config::default().retry,
+        reqwest::Client::new(),
+        downstream,
+        Signer::default(),
+    );
+
+    for n in 0..5 {
+        let job = Job::new(
+            JobKind::LoyaltyDecrement,
+            Method::POST,
+            Service::Loyalty,
+            format!("/api/v1/loyalty/{n}"),
+        )
+        .header("X-User-Name", "Test Max")
+        .ordered_by("Test Max");
+        queue.push(job).unwrap();
+    }
+
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while queue.len() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(*attempts.lock().unwrap(), vec![0, 0, 1, 2, 3, 4]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+fn saga_queue(dir: &std::path::Path, capacity: usize) -> RetryQueue {
+    let (log, _) = JobLog::open(dir.join("queue.jsonl")).unwrap();
+    let dead_letters = DeadLetterStore::open(dir.join("dead_letters.json")).unwrap();
+    RetryQueue::new(
+        log,
+        dead_letters,
+        &QueueConfig {
+            size: capacity,
+            workers: 1,
+        },
+        Config::default().retry,
+        reqwest::Client::new(),
+        Config::default().downstream,
+        Signer::default(),
+    )
+}
+
+#[tokio::test]
+async fn saga_interrupted_by_crash_is_recovered() {
+    let dir = std::env::temp_dir().join(format!("gateway-saga-{}", Uuid::new_v4()));
+    let job = loyalty_job();
+    {
+        let (sagas, unfinished) =
+            Sagas::open(dir.join("sagas.jsonl"), saga_queue(&dir, 10)).unwrap();
+        assert!(unfinished.is_empty());
+
+        let done = sagas.begin("done").await.unwrap();
+        done.complete().await;
+
+        let mut saga = sagas.begin("create_reservation").await.unwrap();
+        saga.step("loyalty", job.clone(), async { Ok::<_, ApiError>(()) })
+            .await
+            .unwrap();
+        // process dies before the saga finishes
+        std::mem::forget(saga);
+    }
+
+    let (_, unfinished) = Sagas::open(dir.join("sagas.jsonl"), saga_queue(&dir, 10)).unwrap();
+    assert_eq!(unfinished.len(), 1);
+    assert_eq!(unfinished[0].name, "create_reservation");
+    assert_eq!(unfinished[0].compensations, vec![job]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn finished_sagas_are_compacted_at_runtime() {
+    let dir = std::env::temp_dir().join(format!("gateway-saga-{}", Uuid::new_v4()));
+    let path = dir.join("sagas.jsonl");
+    let (sagas, _) = Sagas::open(&path, saga_queue(&dir, 10)).unwrap();
+
+    let completed = sagas.begin("completed").await.unwrap();
+    completed.complete().await;
+    let mut aborted = sagas.begin("aborted").await.unwrap();
+    aborted
+        .step("payment", loyalty_job(), async { Ok::<_, ApiError>(()) })
+        .await
+        .unwrap();
+    drop(aborted);
+    let mut running = sagas.begin("running").await.unwrap();
+    running
+        .step("payment", loyalty_job(), async { Ok::<_, ApiError>(()) })
+        .await
+        .unwrap();
+
+    // started and completed, then started, step, done and aborted
+    assert_eq!(sagas.compact().unwrap(), 6);
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+    assert_eq!(sagas.compact().unwrap(), 0);
+
+    std::mem::forget(running);
+    let (_, unfinished) = Sagas::open(&path, saga_queue(&dir, 10)).unwrap();
+    assert_eq!(unfinished.len(), 1);
+    assert_eq!(unfinished[0].name, "running");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn saga_interrupted_mid_step_is_compensated() {
+    let dir = std::env::temp_dir().join(format!("gateway-saga-{}", Uuid::new_v4()));
+    // the loyalty service counts reservations by id and answers 404 if there is nothing to
+    // take away
+    let reservation_uid = Uuid::new_v4();
+    let done = Job::new(
+        JobKind::PaymentCancel,
+        Method::DELETE,
+        Service::Payment,
+        format!("/api/v1/payment/{}", Uuid::new_v4()),
+    );
+    let running = Job::new(
+        JobKind::LoyaltyDecrement,
+        Method::DELETE,
+        Service::Loyalty,
+        format!("/api/v1/loyalty/reservations/{reservation_uid}"),
+    );
+    {
+        let (sagas, _) = Sagas::open(dir.join("sagas.jsonl"), saga_queue(&dir, 10)).unwrap();
+        let mut saga = sagas.begin("create_reservation").await.unwrap();
+        saga.step("payment", done.clone(), async { Ok::<_, ApiError>(()) })
+            .await
+            .unwrap();
+        // process dies while the request of the second step is in flight
+        let step = saga.step(
+            "loyalty",
+            running.clone(),
+            std::future::pending::<Result<(), ApiError>>(),
+        );
+        assert!(tokio::time::timeout(Duration::from_millis(10), step)
+            .await
+            .is_err());
+        std::mem::forget(saga);
+    }
+
+    let queue = saga_queue(&dir, 10);
+    let (sagas, unfinished) = Sagas::open(dir.join("sagas.jsonl"), queue.clone()).unwrap();
+    assert_eq!(unfinished.len(), 1);
+    // the step may or may not have happened, so its compensation tolerates finding nothing
+    assert_eq!(
+        unfinished[0].compensations,
+        vec![done.clone(), running.clone().allow_missing()]
+    );
+
+    sagas.recover(unfinished);
+    assert!(queue.contains(done.id));
+    assert!(queue.contains(running.id));
+    let (_, unfinished) = Sagas::open(dir.join("sagas.jsonl"), saga_queue(&dir, 10)).unwrap();
+    assert!(unfinished.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn saga_compensates_steps_with_unknown_outcome() {
+    let dir = std::env::temp_dir().join(format!("gateway-saga-{}", Uuid::new_v4()));
+    let queue = saga_queue(&dir, 10);
+    let (sagas, _) = Sagas::open(dir.join("sagas.jsonl"), queue.clone()).unwrap();
+    let (rejected, timed_out) = (loyalty_job(), loyalty_job());
+
+    let mut saga = sagas.begin("create_reservation").await.unwrap();
+    let failed = saga
+        .step("payment", rejected.clone(), async {
+            Err::<(), _>(ApiError::from(StatusCode::BAD_REQUEST))
+        })
+        .await;
+    assert!(failed.is_err());
+    // the request may have been applied before the connection broke
+    let failed = saga
+        .step("loyalty", timed_out.clone(), async {
+            Err::<(), _>(ApiError::unavailable("Loyalty"))
+        })
+        .await;
+    assert!(failed.is_err());
+    drop(saga);
+
+    assert!(!queue.contains(rejected.id));
+    assert!(queue.contains(timed_out.id));
+    let (_, unfinished) = Sagas::open(dir.join("sagas.jsonl"), saga_queue(&dir, 10)).unwrap();
+    assert!(unfinished.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn saga_stays_journaled_when_compensation_fails() {
+    let dir = std::env::temp_dir().join(format!("gateway-saga-{}", Uuid::new_v4()));
+    // room for one of the two compensations
+    let queue = saga_queue(&dir, 1);
+    let (sagas, _) = Sagas::open(dir.join("sagas.jsonl"), queue.clone()).unwrap();
+
+    let mut saga = sagas.begin("create_reservation").await.unwrap();
+    for step in ["payment", "loyalty"] {
+        saga.step(step, loyalty_job(), async { Ok::<_, ApiError>(()) })
+            .await
+            .unwrap();
+    }
+    let failed = saga
+        .step("reservation", loyalty_job(), async {
+            Err::<(), _>(ApiError::from(StatusCode::CONFLICT))
+        })
+        .await;
+    assert!(failed.is_err());
+    // compensations can't all be queued, so none are and the saga stays in the journal
+    drop(saga);
+    assert_eq!(queue.len(), 0);
+
+    let (_, unfinished) = Sagas::open(dir.join("sagas.jsonl"), saga_queue(&dir, 0)).unwrap();
+    assert_eq!(unfinished.len(), 1);
+    assert_eq!(unfinished[0].compensations.len(), 2);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn only_sagas_with_queued_compensations_are_counted() {
+    let dir = std::env::temp_dir().join(format!("gateway-saga-{}", Uuid::new_v4()));
+    let (sagas, _) = Sagas::open(dir.join("sagas.jsonl"), saga_queue(&dir, 1)).unwrap();
+
+    // the only step was rejected, there is nothing to undo
+    let mut saga = sagas.begin("metric_rejected").await.unwrap();
+    let failed = saga
+        .step("payment", loyalty_job(), async {
+            Err::<(), _>(ApiError::from(StatusCode::BAD_REQUEST))
+        })
+        .await;
+    assert!(failed.is_err());
+    drop(saga);
+
+    // compensations don't fit into the queue, so none are queued
+    let mut saga = sagas.begin("metric_full").await.unwrap();
+    for step in ["payment", "loyalty"] {
+        saga.step(step, loyalty_job(), async { Ok::<_, ApiError>(()) })
+            .await
+            .unwrap();
+    }
+    drop(saga);
+
+    let mut saga = sagas.begin("metric_queued").await.unwrap();
+    saga.step("payment", loyalty_job(), async { Ok::<_, ApiError>(()) })
+        .await
+        .unwrap();
+    drop(saga);
+
+    let text = crate::metrics::metrics().render();
+    assert!(!text.contains(r#"saga="metric_rejected""#));
+    assert!(!text.contains(r#"saga="metric_full""#));
+    assert!(text.contains(r#"gateway_saga_compensations_total{saga="metric_queued"} 1"#));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn operation_finishes_after_client_disconnects() {
+    let operations = Operations::new(10);
+    let run = operations.run(None, "slow", "Test Max", async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        (
+            StatusCode::CREATED,
+            axum::Json(serde_json::json!({"ok": true})),
+        )
+            .into_response()
+    });
+    // client goes away before the workflow is done
+    assert!(tokio::time::timeout(Duration::from_millis(10), run)
+        .await
+        .is_err());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let ops = operations.list("Test Max");
+    assert_eq!(ops.len(), 1);
+    assert_eq!(ops[0].status, OperationStatus::Succeeded);
+    assert_eq!(ops[0].http_status, Some(201));
+    assert_eq!(ops[0].result, Some(serde_json::json!({"ok": true})));
+}
+
+#[tokio::test]
+async fn operation_is_visible_only_to_its_user() {
+    let operations = Operations::new(10);
+    let resp = operations
+        .run(None, "fail", "Test Max", async {
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        })
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let id = resp.headers()["X-Operation-Id"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let op = operations.get(id, "Test Max").unwrap();
+    assert_eq!(op.status, OperationStatus::Failed);
+    assert!(operations.get(id, "Someone Else").is_none());
+}
+
+#[tokio::test]
+async fn operation_can_be_polled_by_client_chosen_id() {
+    let operations = Operations::new(10);
+    let id = Uuid::new_v4();
+    let run = operations.run(Some(id), "slow", "Test Max", async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        StatusCode::CREATED.into_response()
+    });
+    // the response never reaches the client
+    assert!(tokio::time::timeout(Duration::from_millis(10), run)
+        .await
+        .is_err());
+    let op = operations.get(id, "Test Max").unwrap();
+    assert_eq!(op.status, OperationStatus::Running);
+
+    // the id is not reused for another operation
+    let err = operations
+        .run(Some(id), "slow", "Test Max", async {
+            StatusCode::CREATED.into_response()
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(err.status, StatusCode::CONFLICT);
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let op = operations.get(id, "Test Max").unwrap();
+    assert_eq!(op.status, OperationStatus::Succeeded);
+    assert_eq!(op.http_status, Some(201));
+}
+
+#[tokio::test]
+async fn finished_operations_are_evicted() {
+    let operations = Operations::new(2);
+    for _ in 0..3 {
+        operations
+            .run(None, "noop", "Test Max", async {
+                StatusCode::OK.into_response()
+            })
+            .await
+            .unwrap();
+    }
+    assert_eq!(operations.list("Test Max").len(), 2);
+}
+
+fn reservation_request(hotel: u8) -> serde_json::Value {
+    serde_json::json!({"hotelUid": Uuid::from_bytes([hotel; 16]), "startDate": "2021-10-08", "endDate": "2021-10-11"})
+}
+
+#[test]
+fn idempotency_key_replays_stored_response() {
+    let path = std::env::temp_dir().join(format!("gateway-idem-{}.jsonl", Uuid::new_v4()));
+    let response = serde_json::json!({"reservationUid": Uuid::new_v4()});
+    {
+        let store = IdempotencyStore::open(&path, Duration::from_secs(60)).unwrap();
+        let Ok(Claim::Acquired(claim)) = store.claim("Test Max", "key", &reservation_request(1))
+        else {
+            panic!("key should be new");
+        };
+        assert!(matches!(
+            store.claim("Test Max", "key", &reservation_request(1)),
+            Err(IdempotencyError::InProgress)
+        ));
+        claim.complete(&response);
+    }
+
+    let store = IdempotencyStore::open(&path, Duration::from_secs(60)).unwrap();
+    assert!(matches!(
+        store.claim("Test Max", "key", &reservation_request(1)),
+        Ok(Claim::Replay(r)) if r == response
+    ));
+    assert!(matches!(
+        store.claim("Test Max", "key", &reservation_request(2)),
+        Err(IdempotencyError::Mismatch)
+    ));
+    // keys are scoped to the user
+    assert!(matches!(
+        store.claim("Someone Else", "key", &reservation_request(2)),
+        Ok(Claim::Acquired(_))
+    ));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn idempotency_key_is_freed_when_request_fails() {
+    let path = std::env::temp_dir().join(format!("gateway-idem-{}.jsonl", Uuid::new_v4()));
+    let store = IdempotencyStore::open(&path, Duration::from_secs(60)).unwrap();
+
+    let claim = store.claim("Test Max", "key", &reservation_request(1));
+    drop(claim);
+    assert!(matches!(
+        store.claim("Test Max", "key", &reservation_request(2)),
+        Ok(Claim::Acquired(_))
+    ));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn idempotency_key_expires() {
+    let path = std::env::temp_dir().join(format!("gateway-idem-{}.jsonl", Uuid::new_v4()));
+    let store = IdempotencyStore::open(&path, Duration::ZERO).unwrap();
+
+    let Ok(Claim::Acquired(claim)) = store.claim("Test Max", "key", &reservation_request(1)) else {
+        panic!("key should be new");
+    };
+    claim.complete(&serde_json::json!({}));
+    assert!(matches!(
+        store.claim("Test Max", "key", &reservation_request(2)),
+        Ok(Claim::Acquired(_))
+    ));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn expired_idempotency_keys_are_swept() {
+    let path = std::env::temp_dir().join(format!("gateway-idem-{}.jsonl", Uuid::new_v4()));
+    let store = IdempotencyStore::open(&path, Duration::from_millis(100)).unwrap();
+    let complete = |key: &str| {
+        let Ok(Claim::Acquired(claim)) = store.claim("Test Max", key, &reservation_request(1))
+        else {
+            panic!("key should be new");
+        };
+        claim.complete(&serde_json::json!({}));
+    };
+
+    complete("old");
+    std::thread::sleep(Duration::from_millis(150));
+    complete("new");
+    let Ok(Claim::Acquired(running)) = store.claim("Test Max", "running", &reservation_request(1))
+    else {
+        panic!("key should be new");
+    };
+
+    assert_eq!(store.sweep().unwrap(), 1);
+    let journal = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(journal.lines().count(), 1);
+    assert!(journal.contains("\"key\":\"new\""));
+    // requests in progress are not swept
+    assert!(matches!(
+        store.claim("Test Max", "running", &reservation_request(1)),
+        Err(IdempotencyError::InProgress)
+    ));
+    drop(running);
+
+    // the expired key can be used for another request
+    assert!(matches!(
+        store.claim("Test Max", "old", &reservation_request(2)),
+        Ok(Claim::Acquired(_))
+    ));
+    assert!(matches!(
+        store.claim("Test Max", "new", &reservation_request(1)),
+        Ok(Claim::Replay(_))
+    ));
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn config_layers_env_over_file_over_defaults() {
+    let file = r#"
+        [downstream]
+        reservation = "http://localhost:8070"
+        payment = "http://localhost:8060"
+
+        [queue]
+        size = 10
+
+        [log.modules]
+        "bmstu_rsoi_lab2_gateway::queue" = "trace"
+    "#;
+    let env = |name: &str| match name {
+        "DOWNSTREAM_PAYMENT" => Some("http://127.0.0.1:9060".to_owned()),
+        "QUEUE_WORKERS" => Some("8".to_owned()),
+        "LOG_FORMAT" => Some("json".to_owned()),
+        _ => None,
+    };
+    let config = Config::from_layers(Some(file), env).unwrap();
+
+    assert_eq!(config.downstream.reservation, "http://localhost:8070");
+    assert_eq!(config.downstream.payment, "http://127.0.0.1:9060");
+    assert_eq!(
+        config.downstream.loyalty,
+        Config::default().downstream.loyalty
+    );
+    assert_eq!(config.queue.size, 10);
+    assert_eq!(config.queue.workers, 8);
+    assert_eq!(config.log.format, LogFormat::Json);
+    assert_eq!(
+        config.log.modules["bmstu_rsoi_lab2_gateway::queue"],
+        "trace"
+    );
+}
+
+#[test]
+fn config_breakers_fall_back_to_shared_settings() {
+    let file = r#"
+        [breaker]
+        window_ms = 10000
+
+        [breaker.payment]
+        cooldown_ms = 60000
+    "#;
+    let env = |name: &str| match name {
+        "BREAKER_FAILURE_THRESHOLD" => Some("3".to_owned()),
+        "BREAKER_PAYMENT_COOLDOWN_MS" => Some("30000".to_owned()),
+        "BREAKER_LOYALTY_FAILURE_THRESHOLD" => Some("10".to_owned()),
+        _ => None,
+    };
+    let config = Config::from_layers(Some(file), env).unwrap().breaker;
+
+    let reservation = config.resolve(&config.reservation);
+    assert_eq!(reservation.failure_threshold, 3);
+    assert_eq!(reservation.window, Duration::from_secs(10));
+    assert_eq!(reservation.cooldown, Duration::from_secs(5));
+
+    let payment = config.resolve(&config.payment);
+    assert_eq!(payment.failure_threshold, 3);
+    assert_eq!(payment.cooldown, Duration::from_secs(30));
+
+    let loyalty = config.resolve(&config.loyalty);
+    assert_eq!(loyalty.failure_threshold, 10);
+    assert_eq!(loyalty.window, Duration::from_secs(10));
+    assert_eq!(loyalty.cooldown, Duration::from_secs(5));
+
+    let env = |name: &str| (name == "BREAKER_RESERVATION_WINDOW_MS").then(|| "0".to_owned());
+    assert!(matches!(
+        Config::from_layers(None, env),
+        Err(ConfigError::Invalid(_))
+    ));
+}
+
+#[test]
+fn config_retry_policies_per_job_kind() {
+    let file = r#"
+        [retry.payment_cancel]
+        max_attempts = 3
+    "#;
+    let env =
+        |name: &str| (name == "RETRY_LOYALTY_DECREMENT_MAX_BACKOFF_MS").then(|| "1000".to_owned());
+    let retry = Config::from_layers(Some(file), env).unwrap().retry;
+
+    assert_eq!(retry.policy(JobKind::PaymentCancel).max_attempts, 3);
+    assert_eq!(
+        retry.policy(JobKind::PaymentCancel).max_age,
+        Duration::from_secs(60 * 60)
+    );
+    let loyalty = retry.policy(JobKind::LoyaltyDecrement);
+    assert_eq!(loyalty.max_backoff, Duration::from_secs(1));
+    assert_eq!(loyalty.max_attempts, 150);
+    assert_eq!(
+        retry.policy(JobKind::ReservationCancel).initial_backoff,
+        Duration::from_millis(500)
+    );
+
+    let env = |name: &str| (name == "RETRY_RESERVATION_CANCEL_JITTER").then(|| "1.5".to_owned());
+    assert!(matches!(
+        Config::from_layers(None, env),
+        Err(ConfigError::Invalid(_))
+    ));
+}
+
+#[tokio::test]
+async fn queue_follows_configured_retry_policy() {
+    let dir = std::env::temp_dir().join(format!("gateway-queue-{}", Uuid::new_v4()));
+    let (log, _) = JobLog::open(dir.join("queue.jsonl")).unwrap();
+    let dead_letters = DeadLetterStore::open(dir.join("dead_letters.json")).unwrap();
+    let mut config = Config::default();
+    config.retry.loyalty_decrement.max_attempts = 1;
+    config.downstream.loyalty = "http://127.0.0.1:1".to_owned();
+    let queue = RetryQueue::new(
+        log,
+        dead_letters,
+        &config.queue,
+        config.retry,
+        reqwest::Client::new(),
+        config.downstream,
+        Signer::default(),
+    );
+
+    let job = loyalty_job();
+    queue.push(job.clone()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while queue.dead_letters().is_empty() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let letter = &queue.dead_letters()[0];
+    assert_eq!(letter.job.id, job.id);
+    assert_eq!(letter.job.attempts, 1);
+    assert_eq!(
+        letter.job.deadline,
+        letter.job.created_at + Duration::from_secs(5 * 60)
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn config_rejects_invalid_values() {
+    let env = |name: &str| match name {
+        "SERVER_BIND" => Some("nowhere".to_owned()),
+        "LOG_LEVEL" => Some("loud".to_owned()),
+        "AUTH_ENABLED" => Some("true".to_owned()),
+        "AUTH_USERNAME_CLAIM" => Some(String::new()),
+        _ => None,
+    };
+    let Err(ConfigError::Invalid(errors)) = Config::from_layers(None, env) else {
+        panic!("config should be invalid");
+    };
+    assert_eq!(errors.len(), 3);
+
+    let env = |name: &str| (name == "QUEUE_SIZE").then(|| "many".to_owned());
+    assert!(matches!(
+        Config::from_layers(None, env),
+        Err(ConfigError::Env { .. })
+    ));
+
+    // typos in the file are not silently ignored
+    assert!(matches!(
+        Config::from_layers(Some("[queue]\nsise = 10"), |_| None),
+        Err(ConfigError::Parse(_))
+    ));
+}
+
+#[test]
+fn config_args() {
+    let args = Args::parse(
+        ["--config", "local.toml", "--print-config"]
+            .into_iter()
+            .map(str::to_owned),
+    )
+    .unwrap();
+    assert_eq!(
+        args.config.as_deref(),
+        Some(std::path::Path::new("local.toml"))
+    );
+    assert!(args.print_config);
+
+    assert!(Args::parse(["--verbose".to_owned()].into_iter()).is_err());
+}
+
+#[test]
+fn printed_config_hides_secrets() {
+    let env = |name: &str| (name == "SIGNING_SECRET").then(|| "signing-secret".to_owned());
+    let printed = toml::to_string_pretty(&Config::from_layers(None, env).unwrap()).unwrap();
+    assert!(!printed.contains("signing-secret"));
+    assert!(printed.contains(r#"secret = "***""#));
+    // unset secrets are shown as such
+    assert!(printed.contains(r#"token = """#));
+}
+
+async fn test_app(dir: &std::path::Path, config: Config) -> axum::Router {
+    let queue = saga_queue(dir, 10);
+    let (sagas, _) = Sagas::open(dir.join("sagas.jsonl"), queue.clone()).unwrap();
+    let idempotency =
+        IdempotencyStore::open(dir.join("idempotency.jsonl"), Duration::from_secs(60)).unwrap();
+    crate::app(
+        std::sync::Arc::new(config),
+        reqwest::Client::new(),
+        queue,
+        sagas,
+        idempotency,
+        logging(),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn readiness_reports_unreachable_downstream() {
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    let backend = Router::new().route("/manage/ready", get(|| async { StatusCode::OK }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let dir = std::env::temp_dir().join(format!("gateway-ready-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.reservation = backend_url.clone();
+    config.downstream.payment = backend_url;
+    config.downstream.loyalty = "http://127.0.0.1:1".to_owned();
+    let app = test_app(&dir, config).await;
+
+    let resp = app
+        .oneshot(Request::get("/manage/ready").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    // the gateway still serves requests while loyalty is down
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report["status"], "DEGRADED");
+    assert_eq!(report["components"]["reservation"]["status"], "UP");
+    assert_eq!(report["components"]["payment"]["status"], "UP");
+    assert_eq!(report["components"]["loyalty"]["status"], "DOWN");
+    assert!(report["components"]["loyalty"]["error"].is_string());
+    assert_eq!(
+        report["components"]["queue"]["details"],
+        serde_json::json!({"depth": 0, "capacity": 10})
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn metrics_count_requests_and_fallbacks() {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    let dir = std::env::temp_dir().join(format!("gateway-metrics-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.reservation = "http://127.0.0.1:1".to_owned();
+    config.downstream.payment = "http://127.0.0.1:1".to_owned();
+    config.downstream.loyalty = "http://127.0.0.1:1".to_owned();
+    let app = test_app(&dir, config).await;
+
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::get("/api/v1/me")
+                .header("X-User-Name", "Test Max")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let resp = app
+        .oneshot(Request::get("/manage/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains(r#"http_requests_total{method="GET",route="/api/v1/me",status="503"}"#));
+    assert!(
+        text.contains(r#"http_request_duration_seconds_bucket{method="GET",route="/api/v1/me""#)
+    );
+    assert!(
+        text.contains(r#"gateway_downstream_requests_total{outcome="error",service="loyalty"}"#)
+    );
+    assert!(text.contains(r#"gateway_fallbacks_total{field="loyalty",route="/api/v1/me"}"#));
+    assert!(text.contains("gateway_queue_depth 0"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn traceparent_is_continued_or_restarted() {
+    use crate::trace::TraceContext;
+
+    let parent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let ctx = TraceContext::incoming(Some("req-1"), Some(parent));
+    assert_eq!(ctx.request_id, "req-1");
+    assert_eq!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_ne!(ctx.span_id, "00f067aa0ba902b7");
+    assert_eq!(
+        ctx.traceparent(),
+        format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", ctx.span_id)
+    );
+
+    for invalid in [
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+    ] {
+        let ctx = TraceContext::incoming(Some(""), Some(invalid));
+        assert_ne!(ctx.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.trace_id.len(), 32);
+        assert!(!ctx.request_id.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn trace_context_is_forwarded_to_backends_and_jobs() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::Body,
+        http::{HeaderMap, Request},
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use crate::trace::{self, TraceContext};
+
+    let seen = Arc::new(Mutex::new(None));
+    let backend = Router::new().route(
+        "/api/v1/loyalty",
+        get({
+            let seen = seen.clone();
+            move |headers: HeaderMap| async move {
+                *seen.lock().unwrap() = Some(headers);
+                StatusCode::NOT_FOUND
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = Config::default();
+    config.downstream.loyalty = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let dir = std::env::temp_dir().join(format!("gateway-trace-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let app = test_app(&dir, config).await;
+    let resp = app
+        .oneshot(
+            Request::get("/api/v1/loyalty")
+                .header("X-User-Name", "Test Max")
+                .header("X-Request-Id", "req-42")
+                .header(
+                    "traceparent",
+                    "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                )
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["X-Request-Id"], "req-42");
+
+    let headers = seen.lock().unwrap().take().unwrap();
+    assert_eq!(headers["X-Request-Id"], "req-42");
+    let traceparent = headers["traceparent"].to_str().unwrap();
+    assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert!(!traceparent.contains("00f067aa0ba902b7"));
+
+    let ctx = TraceContext::incoming(Some("req-43"), None);
+    let job = trace::scope(ctx.clone(), async { loyalty_job() }).await;
+    assert!(job
+        .headers
+        .contains(&("X-Request-Id".to_owned(), "req-43".to_owned())));
+    assert!(job
+        .headers
+        .contains(&("traceparent".to_owned(), ctx.traceparent())));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn management_changes_require_admin_token() {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    let dir = std::env::temp_dir().join(format!("gateway-admin-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let post = |path: String, token: Option<&str>| {
+        let mut req = Request::post(path);
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        req.body(Body::empty()).unwrap()
+    };
+
+    // without a configured token nothing can be changed
+    let app = test_app(&dir, Config::default()).await;
+    let resp = app
+        .oneshot(post("/manage/circuits/payment/open".to_owned(), None))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let mut config = Config::default();
+    config.admin.token = "admin-token".to_owned();
+    let app = test_app(&dir, config).await;
+    let replay = format!("/manage/dead-letters/{}/replay", Uuid::new_v4());
+    for (path, token) in [
+        ("/manage/circuits/payment/open".to_owned(), None),
+        (
+            "/manage/circuits/payment/reset".to_owned(),
+            Some("user-token"),
+        ),
+        (replay.clone(), None),
+    ] {
+        let resp = app.clone().oneshot(post(path, token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()["WWW-Authenticate"], "Bearer");
+    }
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::get("/manage/circuits")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let circuits: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert!(circuits
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|c| c["state"] == "CLOSED"));
+
+    let resp = app
+        .clone()
+        .oneshot(post(
+            "/manage/circuits/payment/open".to_owned(),
+            Some("admin-token"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = app
+        .oneshot(post(replay, Some("admin-token")))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn log_levels_change_at_runtime() {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    let dir = std::env::temp_dir().join(format!("gateway-log-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.admin.token = "admin-token".to_owned();
+    let app = test_app(&dir, config).await;
+    let put = |body: &'static str| {
+        Request::put("/manage/log-levels")
+            .header("Authorization", "Bearer admin-token")
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(put(
+            r#"{"modules": {"bmstu_rsoi_lab2_gateway::queue": "debug"}}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let levels = logging().levels();
+    assert_eq!(levels.root, "OFF");
+    assert_eq!(levels.modules["bmstu_rsoi_lab2_gateway::queue"], "DEBUG");
+
+    // nothing is applied if any level is invalid
+    let resp = app
+        .clone()
+        .oneshot(put(
+            r#"{"root": "loud", "modules": {"bmstu_rsoi_lab2_gateway::queue": null}}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        logging().levels().modules["bmstu_rsoi_lab2_gateway::queue"],
+        "DEBUG"
+    );
+
+    let resp = app
+        .oneshot(put(
+            r#"{"modules": {"bmstu_rsoi_lab2_gateway::queue": null}}"#,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(logging().levels().modules.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn payments_are_looked_up_in_one_batch() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        body::Body,
+        http::{HeaderMap, Request},
+        routing::{get, post},
+        Json, Router,
+    };
+    use tower::ServiceExt;
+
+    let (paid, missing) = (Uuid::new_v4(), Uuid::new_v4());
+    let reservation = |payment_uid: Uuid| {
+        serde_json::json!({
+            "reservationUid": Uuid::new_v4(),
+            "hotel": {
+                "hotelUid": Uuid::new_v4(),
+                "name": "Ararat Park Hyatt Moscow",
+                "fullAddress": "Россия, Москва, Неглинная ул., 4",
+                "stars": 5,
+            },
+            "startDate": "2021-10-08T00:00:00+03:00",
+            "endDate": "2021-10-11T00:00:00+03:00",
+            "status": "PAID",
+            "paymentUid": payment_uid,
+        })
+    };
+    let reservations = serde_json::json!([reservation(paid), reservation(missing)]);
+    let batches = Arc::new(AtomicUsize::new(0));
+    let backend = Router::new()
+        .route(
+            "/api/v1/reservations",
+            get(move || async move { Json(reservations) }),
+        )
+        .route(
+            "/api/v1/payment/batch",
+            post({
+                let batches = batches.clone();
+                move |headers: HeaderMap, Json(req): Json<serde_json::Value>| async move {
+                    batches.fetch_add(1, Ordering::SeqCst);
+                    assert_eq!(headers["X-User-Name"], "Test Max");
+                    assert_eq!(req["paymentUids"], serde_json::json!([paid, missing]));
+                    // the second payment is unknown to the payment service
+                    Json(serde_json::json!([
+                        { "paymentUid": paid, "status": "PAID", "price": 9000 }
+                    ]))
+                }
+            }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let dir = std::env::temp_dir().join(format!("gateway-batch-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.reservation = backend_url.clone();
+    config.downstream.payment = backend_url;
+    let app = test_app(&dir, config).await;
+
+    let resp = app
+        .oneshot(
+            Request::get("/api/v1/reservations")
+                .header("X-User-Name", "Test Max")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let resp: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        resp[0]["payment"],
+        serde_json::json!({ "status": "PAID", "price": 9000 })
+    );
+    assert!(resp[1].get("payment").is_none());
+    assert_eq!(batches.load(Ordering::SeqCst), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn api_requires_valid_bearer_token() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::Body,
+        http::{HeaderMap, Request},
+        routing::get,
+        Router,
+    };
+    use jsonwebtoken::{EncodingKey, Header};
+    use tower::ServiceExt;
+
+    const SECRET: &[u8] = b"gateway-test-secret-0123456789abcdef";
+
+    let seen = Arc::new(Mutex::new(None));
+    let backend = Router::new().route(
+        "/api/v1/loyalty",
+        get({
+            let seen = seen.clone();
+            move |headers: HeaderMap| async move {
+                *seen.lock().unwrap() = Some(headers);
+                StatusCode::NOT_FOUND
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = Config::default();
+    config.downstream.loyalty = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let dir = std::env::temp_dir().join(format!("gateway-auth-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let jwks = serde_json::json!({ "keys": [{
+        "kty": "oct",
+        "kid": "test",
+        "alg": "HS256",
+        "k": "Z2F0ZXdheS10ZXN0LXNlY3JldC0wMTIzNDU2Nzg5YWJjZGVm",
+    }]});
+    std::fs::write(dir.join("jwks.json"), jwks.to_string()).unwrap();
+    config.auth.enabled = true;
+    config.auth.jwks_path = dir.join("jwks.json").to_string_lossy().into_owned();
+    let app = test_app(&dir, config).await;
+
+    let token = |secret: &[u8], exp: i64| {
+        let header = Header {
+            kid: Some("test".to_owned()),
+            ..Default::default()
+        };
+        let claims = serde_json::json!({ "preferred_username": "Test Max", "exp": exp });
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    };
+    let request = |token: Option<String>| {
+        let mut req = Request::get("/api/v1/loyalty").header("X-User-Name", "Someone Else");
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        req.body(Body::empty()).unwrap()
+    };
+    let hour_from_now = Utc::now().timestamp() + 3600;
+
+    for req in [
+        request(None),
+        request(Some(token(b"some other secret", hour_from_now))),
+        request(Some(token(SECRET, Utc::now().timestamp() - 3600))),
+    ] {
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(resp.headers()["WWW-Authenticate"], "Bearer");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["message"].is_string());
+    }
+    assert!(seen.lock().unwrap().is_none());
+
+    let resp = app
+        .clone()
+        .oneshot(request(Some(token(SECRET, hour_from_now))))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    // the name from the token replaces the one sent by the client
+    let headers = seen.lock().unwrap().take().unwrap();
+    assert_eq!(headers["X-User-Name"], "Test Max");
+
+    let resp = app
+        .oneshot(Request::get("/manage/health").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn backend_requests_are_signed() {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{body::Bytes, http::HeaderMap, routing::put, Router};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use crate::signature::{self, SignRequest};
+
+    let seen = Arc::new(Mutex::new(None));
+    let backend = Router::new().route(
+        "/api/v1/loyalty",
+        put({
+            let seen = seen.clone();
+            move |headers: HeaderMap, body: Bytes| async move {
+                *seen.lock().unwrap() = Some((headers, body));
+                StatusCode::OK
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/api/v1/loyalty", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let client = reqwest::Client::new();
+    let body = serde_json::json!({ "reservationCount": 1 });
+    client
+        .put(&url)
+        .json(&body)
+        .signed(&Signer::default())
+        .send()
+        .await
+        .unwrap();
+    let (headers, _) = seen.lock().unwrap().take().unwrap();
+    assert!(!headers.contains_key(signature::SIGNATURE));
+
+    client
+        .put(format!("{url}?page=1"))
+        .json(&body)
+        .signed(&Signer::new("internal-secret"))
+        .send()
+        .await
+        .unwrap();
+    let (headers, body) = seen.lock().unwrap().take().unwrap();
+    let header = |name| headers[name].to_str().unwrap();
+    let payload = signature::payload(
+        "PUT",
+        "/api/v1/loyalty?page=1",
+        &headers,
+        header(signature::TIMESTAMP).parse().unwrap(),
+        header(signature::NONCE),
+        &body,
+    );
+    let mut mac = Hmac::<Sha256>::new_from_slice(b"internal-secret").unwrap();
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&hex::decode(header(signature::SIGNATURE)).unwrap())
+        .unwrap();
+}
+
+#[test]
+fn rate_limit_refills_buckets_over_time() {
+    use std::{collections::BTreeMap, time::Instant};
+
+    use axum::http::Method;
+
+    use crate::{
+        config::{Budget, RateLimitConfig, RouteBudgets},
+        rate_limit::{RateLimiter, Scope},
+    };
+
+    let limiter = RateLimiter::new(RateLimitConfig {
+        enabled: true,
+        read: Budget {
+            burst: 2,
+            per_sec: 1.0,
+        },
+        write: Budget {
+            burst: 1,
+            per_sec: 0.5,
+        },
+        routes: BTreeMap::from([(
+            "/api/v1/hotels".to_owned(),
+            RouteBudgets {
+                read: Some(Budget {
+                    burst: 5,
+                    per_sec: 1.0,
+                }),
+                write: None,
+            },
+        )]),
+    });
+    let now = Instant::now();
+    let me = [(Scope::User, "Test Max"), (Scope::Ip, "10.0.0.1")];
+    let check = |route, method, keys: &[(Scope, &str)], at| {
+        limiter.check(route, &method, keys, now + Duration::from_millis(at))
+    };
+
+    assert!(check("/api/v1/me", Method::GET, &me, 0).is_ok());
+    assert!(check("/api/v1/me", Method::GET, &me, 0).is_ok());
+    let Err((Scope::User, wait)) = check("/api/v1/me", Method::GET, &me, 0) else {
+        panic!("third read should be limited");
+    };
+    assert_eq!(wait, Duration::from_secs(1));
+    assert!(check("/api/v1/me", Method::GET, &me, 1000).is_ok());
+
+    // writes, other routes and other users have their own budgets
+    assert!(check("/api/v1/reservations", Method::POST, &me, 1000).is_ok());
+    assert!(check("/api/v1/reservations", Method::POST, &me, 1000).is_err());
+    for _ in 0..5 {
+        assert!(check("/api/v1/hotels", Method::GET, &me, 1000).is_ok());
+    }
+    let other = [(Scope::User, "Other"), (Scope::Ip, "10.0.0.2")];
+    assert!(check("/api/v1/me", Method::GET, &other, 1000).is_ok());
+
+    // the same client IP is limited regardless of the user name
+    let spoofed = [(Scope::User, "Someone Else"), (Scope::Ip, "10.0.0.1")];
+    let Err((Scope::Ip, _)) = check("/api/v1/me", Method::GET, &spoofed, 1000) else {
+        panic!("client IP should be limited");
+    };
+    // nothing was taken from the user bucket by the rejected request
+    assert!(check("/api/v1/me", Method::GET, &spoofed[..1], 1000).is_ok());
+    assert!(check("/api/v1/me", Method::GET, &spoofed[..1], 1000).is_ok());
+}
+
+#[tokio::test]
+async fn over_limit_requests_get_retry_after() {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use crate::config::Budget;
+
+    let dir = std::env::temp_dir().join(format!("gateway-limit-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.loyalty = "http://127.0.0.1:1".to_owned();
+    config.rate_limit.read = Budget {
+        burst: 1,
+        per_sec: 0.1,
+    };
+    let app = test_app(&dir, config).await;
+    let request = || {
+        Request::get("/api/v1/loyalty")
+            .header("X-User-Name", "Rate Limited")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let resp = app.clone().oneshot(request()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let resp = app.clone().oneshot(request()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()["Retry-After"], "10");
+
+    let resp = app
+        .oneshot(Request::get("/manage/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains(
+        r#"gateway_rate_limited_total{class="read",route="/api/v1/loyalty",scope="user"} 1"#
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn backend_errors_are_passed_on() {
+    use axum::{body::Body, http::Request, routing::get, Json, Router};
+    use tower::ServiceExt;
+
+    use crate::error::{ErrorCode, ErrorResponse};
+
+    let backend = Router::new().route(
+        "/api/v1/reservations/{uid}",
+        get(|| async {
+            (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "code": "NOT_FOUND",
+                    "message": "Not Found",
+                    "requestId": "from-backend",
+                })),
+            )
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let dir = std::env::temp_dir().join(format!("gateway-errors-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.reservation = backend_url;
+    config.downstream.loyalty = "http://127.0.0.1:1".to_owned();
+    let app = test_app(&dir, config).await;
+    let error = |resp: axum::response::Response| async move {
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice::<ErrorResponse>(&body).unwrap()
+    };
+
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::get(format!("/api/v1/reservations/{}", Uuid::new_v4()))
+                .header("X-User-Name", "Test Max")
+                .header("X-Request-Id", "missing-reservation")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    let body = error(resp).await;
+    assert_eq!(body.code, ErrorCode::NotFound);
+    assert_eq!(body.message, "Not Found");
+    assert_eq!(body.request_id.as_deref(), Some("missing-reservation"));
+
+    let resp = app
+        .clone()
+        .oneshot(Request::get("/api/v1/loyalty").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(error(resp).await.code, ErrorCode::BadRequest);
+
+    let resp = app
+        .oneshot(
+            Request::get("/api/v1/loyalty")
+                .header("X-User-Name", "Test Max")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = error(resp).await;
+    assert_eq!(body.code, ErrorCode::ServiceUnavailable);
+    assert_eq!(body.message, "Loyalty Service unavailable");
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn invalid_requests_list_every_field() {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+    use tower::ServiceExt;
+
+    use crate::error::{ErrorCode, ErrorResponse};
+
+    let fields = |app: axum::Router, req: Request<Body>| async move {
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.code, ErrorCode::ValidationFailed);
+        body.details
+            .into_iter()
+            .map(|d| d.field)
+            .collect::<Vec<_>>()
+    };
+    let reservation = |start: &str, end: &str| {
+        Request::post("/api/v1/reservations")
+            .header("X-User-Name", "Test Max")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({
+                    "hotelUid": Uuid::new_v4(),
+                    "startDate": start,
+                    "endDate": end,
+                })
+                .to_string(),
+            ))
+            .unwrap()
+    };
+
+    // downstreams are unreachable, invalid requests must not get that far
+    let dir = std::env::temp_dir().join(format!("gateway-validation-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.reservation = "http://127.0.0.1:1".to_owned();
+    let app = test_app(&dir, config.clone()).await;
+
+    let req = Request::get("/api/v1/hotels?page=0&size=1000")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(fields(app.clone(), req).await, ["page", "size"]);
+
+    let req = reservation("2021-10-11", "2021-10-08");
+    assert_eq!(fields(app.clone(), req).await, ["endDate", "startDate"]);
+
+    let today = Utc::now().date_naive();
+    let req = reservation(
+        &today.to_string(),
+        &(today + chrono::Days::new(31)).to_string(),
+    );
+    assert_eq!(fields(app, req).await, ["endDate"]);
+
+    config.validation.allow_past_dates = true;
+    let app = test_app(&dir, config).await;
+    let req = reservation("2021-10-11", "2021-10-08");
+    assert_eq!(fields(app, req).await, ["endDate"]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn malformed_requests_are_rejected_with_error_body() {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+    use tower::ServiceExt;
+
+    use crate::error::{ErrorCode, ErrorResponse};
+
+    let rejected = |app: axum::Router, req: Request<Body>| async move {
+        let resp = app.oneshot(req).await.unwrap();
+        let status = resp.status();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert!(!body.message.is_empty());
+        (status, body.code)
+    };
+
+    let dir = std::env::temp_dir().join(format!("gateway-rejection-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.reservation = "http://127.0.0.1:1".to_owned();
+    let app = test_app(&dir, config).await;
+
+    let req = Request::post("/api/v1/reservations")
+        .header("X-User-Name", "Test Max")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from("{\"hotelUid\":"))
+        .unwrap();
+    assert_eq!(
+        rejected(app.clone(), req).await,
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest)
+    );
+
+    let req = Request::get("/api/v1/hotels?page=first")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        rejected(app.clone(), req).await,
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest)
+    );
+
+    let req = Request::get("/api/v1/reservations/not-a-uuid")
+        .header("X-User-Name", "Test Max")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(
+        rejected(app, req).await,
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest)
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn cancel_reports_failed_payment_and_loyalty_steps() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        body::Body,
+        extract::Path,
+        http::Request,
+        routing::{delete, get},
+        Json, Router,
+    };
+    use tower::ServiceExt;
+
+    // the payment of `foreign` belongs to another user, the one of `refunded` can be canceled
+    let (foreign, refunded, unpaid) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let canceled = Arc::new(AtomicUsize::new(0));
+    let backend = Router::new()
+        .route(
+            "/api/v1/reservations/{uid}",
+            get(|Path(uid): Path<Uuid>| async move {
+                Json(serde_json::json!({
+                    "reservationUid": uid,
+                    "hotel": {
+                        "hotelUid": Uuid::new_v4(),
+                        "name": "Ararat Park Hyatt Moscow",
+                        "fullAddress": "Россия, Москва, Неглинная ул., 4",
+                        "stars": 5,
+                    },
+                    "startDate": "2021-10-08T00:00:00+03:00",
+                    "endDate": "2021-10-11T00:00:00+03:00",
+                    "status": "PAID",
+                    "paymentUid": uid,
+                }))
+            })
+            .delete({
+                let canceled = canceled.clone();
+                move || async move {
+                    canceled.fetch_add(1, Ordering::SeqCst);
+                    StatusCode::NO_CONTENT
+                }
+            }),
+        )
+        .route(
+            "/api/v1/payment/{uid}",
+            get(move |Path(uid): Path<Uuid>| async move {
+                if uid == foreign {
+                    return (
+                        StatusCode::NOT_FOUND,
+                        Json(serde_json::json!({ "code": "NOT_FOUND", "message": "Not Found" })),
+                    )
+                        .into_response();
+                }
+                Json(serde_json::json!({ "paymentUid": uid, "status": "PAID", "price": 9000 }))
+                    .into_response()
+            })
+            .delete(move |Path(uid): Path<Uuid>| async move {
+                if uid == refunded {
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }),
+        )
+        .route(
+            "/api/v1/loyalty",
+            delete(|| async { StatusCode::SERVICE_UNAVAILABLE }),
+        );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, backend).await });
+
+    let dir = std::env::temp_dir().join(format!("gateway-cancel-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut config = Config::default();
+    config.downstream.reservation = backend_url.clone();
+    config.downstream.payment = backend_url.clone();
+    config.downstream.loyalty = backend_url;
+    let app = test_app(&dir, config).await;
+    let cancel = |uid: Uuid| {
+        let app = app.clone();
+        async move {
+            let resp = app
+                .oneshot(
+                    Request::delete(format!("/api/v1/reservations/{uid}"))
+                        .header("X-User-Name", "Test Max")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let status = resp.status();
+            let operation = resp.headers().contains_key("X-Operation-Id");
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(operation);
+            (status, body)
+        }
+    };
+
+    // the reservation is left alone if its payment cannot be canceled
+    let (status, _) = cancel(foreign).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(canceled.load(Ordering::SeqCst), 0);
+
+    let (status, body) = cancel(refunded).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(body["pending"], serde_json::json!(["loyalty"]));
+
+    let (status, body) = cancel(unpaid).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(body["reservationUid"], serde_json::json!(unpaid));
+    assert_eq!(body["pending"], serde_json::json!(["payment", "loyalty"]));
+    assert_eq!(canceled.load(Ordering::SeqCst), 2);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
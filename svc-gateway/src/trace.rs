@@ -0,0 +1,168 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+pub const REQUEST_ID: &str = "X-Request-Id";
+pub const TRACEPARENT: &str = "traceparent";
+
+tokio::task_local! {
+    static CURRENT: Arc<TraceContext>;
+}
+
+/// Request id and W3C trace context of the request being handled
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub request_id: String,
+    pub trace_id: String,
+    /// Span of this service, passed as the parent to downstream services
+    pub span_id: String,
+    pub flags: String,
+}
+
+impl TraceContext {
+    /// Continues the incoming trace, or starts a new one if the values are missing or malformed
+    pub fn incoming(request_id: Option<&str>, traceparent: Option<&str>) -> Self {
+        let request_id = request_id
+            .filter(|id| is_valid_request_id(id))
+            .map_or_else(|| Uuid::new_v4().to_string(), str::to_owned);
+        let (trace_id, flags) = traceparent
+            .and_then(parse_traceparent)
+            .unwrap_or_else(|| (Uuid::new_v4().simple().to_string(), "01".to_owned()));
+        Self {
+            request_id,
+            trace_id,
+            span_id: Uuid::new_v4().simple().to_string()[..16].to_owned(),
+            flags,
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        Self::incoming(get(REQUEST_ID), get(TRACEPARENT))
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+
+    pub fn current() -> Option<Arc<Self>> {
+        CURRENT.try_with(Arc::clone).ok()
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Returns trace id and flags of a valid `traceparent`
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    // later versions may append fields, version 00 has exactly four
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if !is_hex(trace_id, 32)
+        || !is_hex(parent_id, 16)
+        || !is_hex(flags, 2)
+        || trace_id.bytes().all(|b| b == b'0')
+        || parent_id.bytes().all(|b| b == b'0')
+    {
+        return None;
+    }
+    Some((trace_id.to_owned(), flags.to_owned()))
+}
+
+/// Headers that continue the current trace in a request to another service
+pub fn outgoing_headers() -> Vec<(String, String)> {
+    match TraceContext::current() {
+        Some(ctx) => vec![
+            (REQUEST_ID.to_owned(), ctx.request_id.clone()),
+            (TRACEPARENT.to_owned(), ctx.traceparent()),
+        ],
+        None => Vec::new(),
+    }
+}
+
+pub trait TraceRequest {
+    /// Adds the current request id and `traceparent`
+    fn traced(self) -> Self;
+}
+
+impl TraceRequest for reqwest::RequestBuilder {
+    fn traced(mut self) -> Self {
+        for (name, value) in outgoing_headers() {
+            self = self.header(name, value);
+        }
+        self
+    }
+}
+
+/// Runs `f` with `ctx` as the current context. Log records written meanwhile carry its ids
+pub fn scope<F: Future>(ctx: TraceContext, f: F) -> Traced<F> {
+    Traced {
+        ctx: Some(Arc::new(ctx)),
+        inner: Box::pin(f),
+    }
+}
+
+/// Keeps the current context for `f`, which is about to be spawned as a separate task
+pub fn inherit<F: Future>(f: F) -> Traced<F> {
+    Traced {
+        ctx: TraceContext::current(),
+        inner: Box::pin(f),
+    }
+}
+
+pub struct Traced<F> {
+    ctx: Option<Arc<TraceContext>>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let Some(ctx) = this.ctx.clone() else {
+            return this.inner.as_mut().poll(cx);
+        };
+        // the task may be polled on any worker thread, so the log context is set per poll
+        let _mdc = log_mdc::extend_scoped([
+            ("request_id", ctx.request_id.as_str()),
+            ("trace_id", ctx.trace_id.as_str()),
+            ("span_id", ctx.span_id.as_str()),
+        ]);
+        CURRENT.sync_scope(ctx, || this.inner.as_mut().poll(cx))
+    }
+}
+
+/// Accepts or generates `X-Request-Id` and `traceparent` and echoes the request id back
+pub async fn propagate(req: Request, next: Next) -> Response {
+    let ctx = TraceContext::from_headers(req.headers());
+    let request_id = HeaderValue::from_str(&ctx.request_id);
+
+    let mut resp = scope(ctx, next.run(req)).await;
+    if let Ok(id) = request_id {
+        resp.headers_mut().insert(REQUEST_ID, id);
+    }
+    resp
+}
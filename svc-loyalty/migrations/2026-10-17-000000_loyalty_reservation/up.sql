@@ -0,0 +1,7 @@
+-- Reservations counted by the loyalty program. Requests naming a reservation are applied
+-- once, so the gateway can repeat them and knows whether there is anything to undo
+CREATE TABLE loyalty_reservation
+(
+    reservation_uid UUID PRIMARY KEY,
+    username        VARCHAR(80) NOT NULL
+);
@@ -0,0 +1,127 @@
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::{
+    db::{self, DbError, DbPool},
+    dto::Loyalty,
+    schema::{loyalty, loyalty_reservation},
+};
+
+#[derive(Debug, Clone)]
+pub struct LoyaltyRepository {
+    pool: DbPool,
+}
+
+impl LoyaltyRepository {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn get(&self, username: String) -> Result<Loyalty, DbError> {
+        db::run(&self.pool, "get_loyalty", move |conn| {
+            loyalty::table
+                .filter(loyalty::username.eq(username))
+                .select(Loyalty::as_select())
+                .get_result(conn)
+        })
+        .await
+    }
+
+    /// Counts one more reservation, creating the record on the first one
+    pub async fn increment(&self, username: String) -> Result<(), DbError> {
+        db::run(&self.pool, "increment_loyalty", move |conn| {
+            conn.transaction(|conn| increment(conn, &username))
+        })
+        .await
+    }
+
+    pub async fn decrement(&self, username: String) -> Result<(), DbError> {
+        db::run(&self.pool, "decrement_loyalty", move |conn| {
+            conn.transaction(|conn| decrement(conn, &username))
+        })
+        .await
+    }
+
+    /// Counts the reservation unless it is counted already
+    pub async fn add_reservation(
+        &self,
+        username: String,
+        reservation_uid: Uuid,
+    ) -> Result<(), DbError> {
+        db::run(&self.pool, "add_loyalty_reservation", move |conn| {
+            conn.transaction(|conn| {
+                let added = diesel::insert_into(loyalty_reservation::table)
+                    .values((
+                        loyalty_reservation::reservation_uid.eq(reservation_uid),
+                        loyalty_reservation::username.eq(&username),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+                if added == 0 {
+                    return Ok(());
+                }
+                increment(conn, &username)
+            })
+        })
+        .await
+    }
+
+    /// Stops counting the reservation. Fails with `NotFound` if it was not counted, so
+    /// nothing is taken away twice
+    pub async fn remove_reservation(
+        &self,
+        username: String,
+        reservation_uid: Uuid,
+    ) -> Result<(), DbError> {
+        db::run(&self.pool, "remove_loyalty_reservation", move |conn| {
+            conn.transaction(|conn| {
+                let removed = diesel::delete(loyalty_reservation::table)
+                    .filter(loyalty_reservation::reservation_uid.eq(reservation_uid))
+                    .filter(loyalty_reservation::username.eq(&username))
+                    .execute(conn)?;
+                if removed == 0 {
+                    return Err(diesel::result::Error::NotFound);
+                }
+                decrement(conn, &username)
+            })
+        })
+        .await
+    }
+}
+
+fn increment(conn: &mut PgConnection, username: &str) -> QueryResult<()> {
+    let counter = diesel::insert_into(loyalty::table)
+        .values(&Loyalty::new(username.to_owned()))
+        .on_conflict(loyalty::username)
+        .do_update()
+        .set(loyalty::reservation_count.eq(loyalty::reservation_count + 1))
+        .returning(loyalty::reservation_count)
+        .get_result(conn)?;
+
+    if counter == 10 || counter == 20 {
+        update_status(conn, username, counter)?;
+    }
+    Ok(())
+}
+
+fn decrement(conn: &mut PgConnection, username: &str) -> QueryResult<()> {
+    let counter = diesel::update(loyalty::table)
+        .filter(loyalty::username.eq(username))
+        .set(loyalty::reservation_count.eq(loyalty::reservation_count - 1))
+        .returning(loyalty::reservation_count)
+        .get_result(conn)?;
+
+    if counter == 9 || counter == 19 {
+        update_status(conn, username, counter)?;
+    }
+    Ok(())
+}
+
+fn update_status(conn: &mut PgConnection, username: &str, counter: i32) -> QueryResult<()> {
+    let (status, discount) = Loyalty::loyalty_from_counter(counter);
+    diesel::update(loyalty::table)
+        .filter(loyalty::username.eq(username))
+        .set((loyalty::status.eq(status), loyalty::discount.eq(discount)))
+        .execute(conn)?;
+    Ok(())
+}
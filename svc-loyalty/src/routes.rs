@@ -1,12 +1,28 @@
+use std::{collections::BTreeMap, sync::atomic::Ordering};
+
 use axum::{
     extract::State,
-    http::{HeaderMap, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
-    Json,
 };
-use diesel::{prelude::*, result::Error as DieselError};
+use uuid::Uuid;
+
+use crate::{
+    db,
+    dto::*,
+    error::{ApiError, ErrorResponse, Json, Path},
+    logger::{LogLevels, LogLevelsUpdate},
+    metrics::metrics,
+    AppState,
+};
 
-use crate::{dto::*, schema::loyalty, AppState};
+/// Name of the user the request is made for, required by all `/api` routes
+fn username(headers: &HeaderMap) -> Result<&str, ApiError> {
+    headers
+        .get("X-User-Name")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| ApiError::bad_request("X-User-Name header is required"))
+}
 
 #[utoipa::path(
     get,
@@ -19,6 +35,112 @@ pub async fn check_health() -> impl IntoResponse {
     StatusCode::OK
 }
 
+#[utoipa::path(
+    get,
+    path = "/manage/metrics",
+    responses(
+        (
+            status = OK,
+            description = "Метрики в формате Prometheus",
+            body = String,
+            content_type = "text/plain; version=0.0.4",
+        ),
+    )
+)]
+pub async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/log-levels",
+    responses(
+        (
+            status = OK,
+            description = "Текущие уровни логирования",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn get_log_levels(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.logging.levels())
+}
+
+#[utoipa::path(
+    put,
+    path = "/manage/log-levels",
+    request_body = LogLevelsUpdate,
+    responses(
+        (
+            status = OK,
+            description = "Уровни логирования изменены",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+        (status = BAD_REQUEST, description = "Неизвестный уровень логирования", body = ErrorResponse),
+        (status = UNAUTHORIZED, body = ErrorResponse, description = "Нужен токен администратора"),
+    )
+)]
+pub async fn put_log_levels(
+    State(state): State<AppState>,
+    Json(update): Json<LogLevelsUpdate>,
+) -> impl IntoResponse {
+    match state.logging.update(update) {
+        Ok(levels) => Json(levels).into_response(),
+        Err(errors) => ApiError::bad_request(errors.join("; ")).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/ready",
+    responses(
+        (
+            status = OK,
+            description = "Сервис готов обрабатывать запросы",
+            body = ReadinessResponse,
+            content_type = "application/json",
+        ),
+        (
+            status = SERVICE_UNAVAILABLE,
+            description = "База данных недоступна или миграции не применены",
+            body = ReadinessResponse,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn check_ready(State(state): State<AppState>) -> impl IntoResponse {
+    let database = ComponentHealth::probe(db::ping(&state.pool)).await;
+    let migrations = if database.status == HealthStatus::Down {
+        ComponentHealth::down(0.0, "database is unavailable")
+    } else if !state.ready.load(Ordering::Acquire) {
+        ComponentHealth::down(0.0, "migrations are not applied yet")
+    } else {
+        ComponentHealth::probe(async {
+            match db::has_pending_migrations(&state.pool).await {
+                Ok(false) => Ok(()),
+                Ok(true) => Err("some migrations are not applied".to_owned()),
+                Err(e) => Err(e.to_string()),
+            }
+        })
+        .await
+    };
+
+    let resp = ReadinessResponse::new(BTreeMap::from([
+        ("database".to_owned(), database),
+        ("migrations".to_owned(), migrations),
+    ]));
+    let status = match resp.status {
+        HealthStatus::Up => StatusCode::OK,
+        HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(resp))
+}
+
 #[utoipa::path(
     get,
     path = "/api/v1/loyalty",
@@ -29,6 +151,11 @@ pub async fn check_health() -> impl IntoResponse {
             body = LoyaltyResponse,
             content_type = "application/json",
         ),
+        (
+            status = NOT_FOUND,
+            description = "Пользователь не участвует в программе лояльности",
+            body = ErrorResponse,
+        ),
     ),
     params(
         ("X-User-Name", Header, description = "Имя пользователя")
@@ -37,25 +164,10 @@ pub async fn check_health() -> impl IntoResponse {
 pub async fn get_loyalty(
     State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-
-    let res = loyalty::table
-        .filter(loyalty::username.eq(username))
-        .select(Loyalty::as_select())
-        .get_result::<Loyalty>(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
 
+    let res = state.loyalties.get(username.to_owned()).await?;
     let res = LoyaltyResponse::from(res);
 
     Ok(Json(res))
@@ -78,35 +190,10 @@ pub async fn get_loyalty(
 pub async fn delete_loyalty(
     State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-
-    let counter = diesel::update(loyalty::table)
-        .filter(loyalty::username.eq(username))
-        .set(loyalty::reservation_count.eq(loyalty::reservation_count - 1))
-        .returning(loyalty::reservation_count)
-        .get_result(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
-
-    if counter == 9 || counter == 19 {
-        let (status, discount) = Loyalty::loyalty_from_counter(counter);
-
-        diesel::update(loyalty::table)
-            .filter(loyalty::username.eq(username))
-            .set((loyalty::status.eq(status), loyalty::discount.eq(discount)))
-            .execute(conn)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+
+    state.loyalties.decrement(username.to_owned()).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -124,37 +211,67 @@ pub async fn delete_loyalty(
 pub async fn put_loyalty(
     State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-
-    let counter = diesel::insert_into(loyalty::table)
-        .values(&Loyalty::new(username.to_owned()))
-        .on_conflict(loyalty::username)
-        .do_update()
-        .set(loyalty::reservation_count.eq(loyalty::reservation_count + 1))
-        .returning(loyalty::reservation_count)
-        .get_result(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
-
-    if counter == 10 || counter == 20 {
-        let (status, discount) = Loyalty::loyalty_from_counter(counter);
-
-        diesel::update(loyalty::table)
-            .filter(loyalty::username.eq(username))
-            .set((loyalty::status.eq(status), loyalty::discount.eq(discount)))
-            .execute(conn)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+
+    state.loyalties.increment(username.to_owned()).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/loyalty/reservations/{reservationUid}",
+    responses(
+        (status = NO_CONTENT, description = "Бронирование учтено в программе лояльности"),
+    ),
+    params(
+        ("X-User-Name", Header, description = "Имя пользователя"),
+        ("reservationUid", Path, description = "Идентификатор брони, повторный запрос ничего не меняет"),
+    ),
+)]
+pub async fn put_loyalty_reservation(
+    State(state): State<AppState>,
+    Path(reservation_uid): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+
+    state
+        .loyalties
+        .add_reservation(username.to_owned(), reservation_uid)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/loyalty/reservations/{reservationUid}",
+    responses(
+        (status = NO_CONTENT, description = "Бронирование вычтено из программы лояльности"),
+        (
+            status = NOT_FOUND,
+            description = "Бронирование не было учтено или уже вычтено",
+            body = ErrorResponse,
+        ),
+    ),
+    params(
+        ("X-User-Name", Header, description = "Имя пользователя"),
+        ("reservationUid", Path, description = "Идентификатор брони"),
+    ),
+)]
+pub async fn delete_loyalty_reservation(
+    State(state): State<AppState>,
+    Path(reservation_uid): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+
+    state
+        .loyalties
+        .remove_reservation(username.to_owned(), reservation_uid)
+        .await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -11,3 +11,13 @@ diesel::table! {
         discount -> Int4,
     }
 }
+
+diesel::table! {
+    loyalty_reservation (reservation_uid) {
+        reservation_uid -> Uuid,
+        #[max_length = 80]
+        username -> Varchar,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(loyalty, loyalty_reservation,);
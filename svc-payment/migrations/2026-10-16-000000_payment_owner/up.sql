@@ -0,0 +1,6 @@
+-- Payments created before owners were recorded get an empty owner. No user can read or
+-- cancel them until scripts/backfill-payment-owners.sh copies the owners from reservations
+ALTER TABLE payment
+    ADD COLUMN username VARCHAR(80) NOT NULL DEFAULT '';
+ALTER TABLE payment
+    ALTER COLUMN username DROP DEFAULT;
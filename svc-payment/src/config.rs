@@ -0,0 +1,297 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Display,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+/// Read when neither `--config` nor `CONFIG_FILE` is given, if it exists
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+pub const CONFIG_PATH_VAR: &str = "CONFIG_FILE";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub database: DatabaseConfig,
+    pub signing: SigningConfig,
+    pub admin: AdminConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub worker_threads: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,
+    pub format: LogFormat,
+    /// Levels of individual modules, e.g. `"bmstu_rsoi_lab2_gateway::queue" = "debug"`
+    pub modules: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per record, request ids are under `mdc`
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Has no default, set it through `DATABASE_URL`
+    #[serde(serialize_with = "redacted")]
+    pub url: String,
+    pub pool_size: u32,
+    pub min_idle: u32,
+    /// How long a request waits for a free connection before failing with 503
+    pub acquire_timeout_ms: u64,
+    pub idle_timeout_ms: u64,
+    /// Check connections before handing them out of the pool
+    pub health_check: bool,
+    /// Backoff between attempts to connect and apply migrations at startup
+    pub startup_retry_initial_ms: u64,
+    pub startup_retry_max_ms: u64,
+}
+
+/// Access to the `/manage` endpoints that change the service state
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Bearer token such requests must carry. They are all refused if it is empty
+    #[serde(serialize_with = "redacted")]
+    pub token: String,
+}
+
+/// Verification of requests signed by the gateway
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Secret shared with the gateway. `/api` requests are accepted unsigned if it is empty
+    #[serde(serialize_with = "redacted")]
+    pub secret: String,
+    /// How far the signature timestamp may drift from the local clock
+    pub max_skew_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig {
+                bind: "0.0.0.0:8060".to_owned(),
+                worker_threads: 4,
+            },
+            log: LogConfig {
+                level: "debug".to_owned(),
+                format: LogFormat::Text,
+                modules: BTreeMap::new(),
+            },
+            database: DatabaseConfig {
+                url: String::new(),
+                pool_size: 10,
+                min_idle: 2,
+                acquire_timeout_ms: 2000,
+                idle_timeout_ms: 10 * 60 * 1000,
+                health_check: true,
+                startup_retry_initial_ms: 500,
+                startup_retry_max_ms: 10000,
+            },
+            signing: SigningConfig {
+                secret: String::new(),
+                max_skew_secs: 300,
+            },
+            admin: AdminConfig {
+                token: String::new(),
+            },
+        }
+    }
+}
+
+impl Config {
+    /// Builds configuration from defaults, then the TOML file, then environment variables
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let file = match path {
+            Some(p) => Some(p.to_path_buf()),
+            None => env::var_os(CONFIG_PATH_VAR)
+                .map(PathBuf::from)
+                .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|p| p.exists())),
+        };
+        let file = match file {
+            Some(p) => Some(fs::read_to_string(&p).map_err(|e| ConfigError::Io(p, e))?),
+            None => None,
+        };
+
+        Self::from_layers(file.as_deref(), |name| env::var(name).ok())
+    }
+
+    /// Layers `file` contents and variables returned by `env` over the defaults
+    pub fn from_layers(
+        file: Option<&str>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let mut table = toml::Table::try_from(Self::default()).map_err(ConfigError::Serialize)?;
+        if let Some(file) = file {
+            merge(&mut table, file.parse().map_err(ConfigError::Parse)?);
+        }
+        apply_env(&mut table, env)?;
+
+        let config: Self = table.try_into().map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        if SocketAddr::from_str(&self.server.bind).is_err() {
+            errors.push(format!(
+                "server.bind: invalid address {:?}",
+                self.server.bind
+            ));
+        }
+        if self.server.worker_threads == 0 {
+            errors.push("server.worker_threads: must be positive".to_owned());
+        }
+        if LevelFilter::from_str(&self.log.level).is_err() {
+            errors.push(format!("log.level: unknown level {:?}", self.log.level));
+        }
+        for (module, level) in &self.log.modules {
+            if LevelFilter::from_str(level).is_err() {
+                errors.push(format!("log.modules.{module}: unknown level {level:?}"));
+            }
+        }
+        if !self.database.url.starts_with("postgres://")
+            && !self.database.url.starts_with("postgresql://")
+        {
+            errors.push("database.url: expected a postgres:// URL".to_owned());
+        }
+        if self.database.pool_size == 0 {
+            errors.push("database.pool_size: must be positive".to_owned());
+        }
+        if self.database.min_idle > self.database.pool_size {
+            errors.push("database.min_idle: must not exceed database.pool_size".to_owned());
+        }
+        if self.database.acquire_timeout_ms == 0 {
+            errors.push("database.acquire_timeout_ms: must be positive".to_owned());
+        }
+        if self.database.startup_retry_initial_ms == 0 {
+            errors.push("database.startup_retry_initial_ms: must be positive".to_owned());
+        }
+        if self.signing.max_skew_secs == 0 {
+            errors.push("signing.max_skew_secs: must be positive".to_owned());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+}
+
+/// Command line arguments
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub print_config: bool,
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    parsed.config = Some(args.next().ok_or("--config expects a path")?.into())
+                }
+                "--print-config" => parsed.print_config = true,
+                _ => return Err(format!("unknown argument {arg:?}")),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+    Env { name: String, value: String },
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            Self::Parse(e) => write!(f, "{e}"),
+            Self::Serialize(e) => write!(f, "{e}"),
+            Self::Env { name, value } => write!(f, "{name}: invalid value {value:?}"),
+            Self::Invalid(errors) => f.write_str(&errors.join("; ")),
+        }
+    }
+}
+
+/// Serializes a secret as `***` so printing the config does not reveal it. An empty value
+/// stays empty to show that it is not set
+fn redacted<S: serde::Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(if value.is_empty() { "" } else { "***" })
+}
+
+fn merge(base: &mut toml::Table, other: toml::Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(o)) => merge(b, o),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// every `section.key` can be overridden by the `SECTION_KEY` variable
+fn apply_env(
+    table: &mut toml::Table,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    for (section, values) in table.iter_mut() {
+        let Some(values) = values.as_table_mut() else {
+            continue;
+        };
+        for (key, value) in values.iter_mut() {
+            let name = format!("{section}_{key}").to_uppercase();
+            let Some(raw) = env(&name) else {
+                continue;
+            };
+            let invalid = || ConfigError::Env {
+                name: name.clone(),
+                value: raw.clone(),
+            };
+            *value = match value {
+                toml::Value::String(_) => toml::Value::String(raw.clone()),
+                toml::Value::Integer(_) => {
+                    toml::Value::Integer(raw.parse().map_err(|_| invalid())?)
+                }
+                toml::Value::Float(_) => toml::Value::Float(raw.parse().map_err(|_| invalid())?),
+                toml::Value::Boolean(_) => {
+                    toml::Value::Boolean(raw.parse().map_err(|_| invalid())?)
+                }
+                _ => return Err(invalid()),
+            };
+        }
+    }
+    Ok(())
+}
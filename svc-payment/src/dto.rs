@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::Instant};
 
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -6,11 +6,24 @@ use utoipa::ToSchema;
 use uuid::Uuid;
 
 #[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct PaymentRequest {
+    /// Идентификатор оплаты, выбранный вызывающей стороной. Если не указан, создаётся новый
+    #[serde(default)]
+    pub payment_uid: Option<Uuid>,
     pub status: PaymentStatus,
     pub price: i32,
 }
 
+/// Largest number of payments looked up by one batch request
+pub const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentBatchRequest {
+    pub payment_uids: Vec<Uuid>,
+}
+
 #[derive(Serialize, Queryable, Selectable, Insertable, ToSchema)]
 #[diesel(table_name = crate::schema::payment)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
@@ -19,14 +32,18 @@ pub struct Payment {
     pub payment_uid: Uuid,
     pub status: String,
     pub price: i32,
+    /// Пользователь, которому принадлежит оплата
+    #[serde(skip)]
+    pub username: String,
 }
 
-impl From<PaymentRequest> for Payment {
-    fn from(value: PaymentRequest) -> Self {
-        Self {
-            payment_uid: Uuid::new_v4(),
-            status: value.status.to_string(),
-            price: value.price,
+impl PaymentRequest {
+    pub fn into_payment(self, username: String) -> Payment {
+        Payment {
+            payment_uid: self.payment_uid.unwrap_or_else(Uuid::new_v4),
+            status: self.status.to_string(),
+            price: self.price,
+            username,
         }
     }
 }
@@ -46,3 +63,64 @@ impl Display for PaymentStatus {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+/// Результат проверки одной зависимости сервиса
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    /// Длительность проверки в миллисекундах
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ComponentHealth {
+    /// Runs `check` and records how long it took
+    pub async fn probe<E: Display>(check: impl Future<Output = Result<(), E>>) -> Self {
+        let started = Instant::now();
+        let res = check.await;
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        match res {
+            Ok(()) => Self {
+                status: HealthStatus::Up,
+                latency_ms,
+                error: None,
+            },
+            Err(e) => Self::down(latency_ms, e),
+        }
+    }
+
+    pub fn down(latency_ms: f64, error: impl Display) -> Self {
+        Self {
+            status: HealthStatus::Down,
+            latency_ms,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    /// `UP`, только если готовы все компоненты
+    pub status: HealthStatus,
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+impl ReadinessResponse {
+    pub fn new(components: BTreeMap<String, ComponentHealth>) -> Self {
+        let status = if components.values().all(|c| c.status == HealthStatus::Up) {
+            HealthStatus::Up
+        } else {
+            HealthStatus::Down
+        };
+        Self { status, components }
+    }
+}
@@ -1,73 +1,199 @@
-use std::env;
+use std::{
+    env, process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use diesel::prelude::*;
+use admin::Admin;
+use config::{Args, Config, DatabaseConfig};
+use db::DbPool;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dto::*;
+use logger::Logging;
+use repository::PaymentRepository;
 use routes::*;
+use signature::Verifier;
 use tokio::net::TcpListener;
 use utoipa::OpenApi;
 use utoipa_axum::{router::OpenApiRouter, routes};
 use utoipa_swagger_ui::SwaggerUi;
 
+mod admin;
+mod config;
+mod db;
 mod dto;
+mod error;
 mod logger;
+mod metrics;
+mod repository;
 mod routes;
 mod schema;
+mod signature;
+mod trace;
 
 #[cfg(test)]
 mod tests;
 
 #[derive(utoipa::OpenApi)]
 #[openapi(
-    paths(post_payment, delete_payment, get_payment),
-    components(schemas(PaymentStatus, Payment, PaymentRequest))
+    paths(
+        check_health,
+        check_ready,
+        get_metrics,
+        get_log_levels,
+        put_log_levels,
+        post_payment,
+        delete_payment,
+        get_payment,
+        get_payments
+    ),
+    components(schemas(
+        PaymentStatus,
+        Payment,
+        PaymentRequest,
+        PaymentBatchRequest,
+        ReadinessResponse,
+        logger::LogLevels,
+        logger::LogLevelsUpdate,
+        ComponentHealth,
+        HealthStatus,
+        error::ErrorResponse,
+        error::ErrorCode,
+        error::FieldError
+    ))
 )]
 struct ApiDoc;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
-pub const SERVICE_ENDPOINT: &str = "0.0.0.0:8060";
 
 #[derive(Debug, Clone)]
 struct AppState {
-    database_url: String,
+    pool: DbPool,
+    /// Set once the database is reachable and migrations are applied
+    ready: Arc<AtomicBool>,
+    logging: Logging,
+    payments: PaymentRepository,
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() {
-    let database_url =
-        env::var("DATABASE_URL").expect("DATABASE_URL environment variable was not specified");
+fn main() {
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\nUsage: bmstu-rsoi-lab2-payment [--config <path>] [--print-config]");
+        process::exit(2)
+    });
+    let config = Config::load(args.config.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {e}");
+        process::exit(1)
+    });
+    if args.print_config {
+        print!("{}", toml::to_string_pretty(&config).unwrap());
+        return;
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.server.worker_threads)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(config));
+}
 
-    let _logger_handler = logger::init();
+async fn run(config: Config) {
+    let logging = logger::init(&config.log);
     log::debug!("Logger initialized. Hello, world!");
 
-    let app = app(database_url).await;
+    let pool = db::pool(&config.database);
+    let ready = Arc::new(AtomicBool::new(false));
+    tokio::spawn(init_db(
+        pool.clone(),
+        config.database.clone(),
+        ready.clone(),
+    ));
+    let app = app(
+        pool,
+        ready,
+        logging,
+        Verifier::new(&config.signing),
+        Admin::new(&config.admin),
+    )
+    .await;
 
-    log::info!("Listening on {}", SERVICE_ENDPOINT);
-    let listener = TcpListener::bind(SERVICE_ENDPOINT).await.unwrap();
+    log::info!("Listening on {}", config.server.bind);
+    let listener = TcpListener::bind(&config.server.bind).await.unwrap();
     axum::serve(listener, app.into_make_service())
         .await
         .unwrap();
 }
 
-async fn app(database_url: String) -> axum::Router {
-    init_db(database_url.as_str());
-
+async fn app(
+    pool: DbPool,
+    ready: Arc<AtomicBool>,
+    logging: Logging,
+    verifier: Option<Verifier>,
+    admin: Admin,
+) -> axum::Router {
     let swagger = SwaggerUi::new("/swagger-ui").url("/openapi.json", ApiDoc::openapi());
-    let state = AppState { database_url };
+    let state = AppState {
+        payments: PaymentRepository::new(pool.clone()),
+        ready,
+        logging,
+        pool,
+    };
     let app = OpenApiRouter::with_openapi(ApiDoc::openapi())
         .routes(routes!(routes::check_health))
+        .routes(routes!(routes::check_ready))
+        .routes(routes!(routes::get_metrics))
+        .routes(routes!(routes::get_log_levels, routes::put_log_levels))
         .routes(routes!(routes::post_payment))
+        .routes(routes!(routes::get_payments))
         .routes(routes!(routes::get_payment, routes::delete_payment))
         .with_state(state);
+    let app = match verifier {
+        Some(verifier) => app.layer(axum::middleware::from_fn_with_state(
+            verifier,
+            signature::verify,
+        )),
+        None => app,
+    };
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(
+            admin,
+            admin::authorize,
+        ))
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(trace::propagate));
 
     axum::Router::from(app).merge(swagger)
 }
 
-fn init_db(database_url: &str) {
-    let conn = &mut PgConnection::establish(database_url)
-        .expect("Failed to establish connection to database");
-    let result = conn.run_pending_migrations(MIGRATIONS);
-    if let Err(e) = result {
-        panic!("Failed to initialize DB: {e}");
+/// Keeps connecting and applying migrations until both succeed, so the service can start
+/// before the database does
+async fn init_db(pool: DbPool, config: DatabaseConfig, ready: Arc<AtomicBool>) {
+    let mut backoff = Duration::from_millis(config.startup_retry_initial_ms);
+    loop {
+        let pool = pool.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            let conn = &mut pool.get().map_err(|e| e.to_string())?;
+            conn.run_pending_migrations(MIGRATIONS)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+        match res {
+            Ok(()) => {
+                log::info!("Database is ready");
+                ready.store(true, Ordering::Release);
+                return;
+            }
+            Err(e) => {
+                log::warn!("Failed to initialize DB, retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_millis(config.startup_retry_max_ms));
+            }
+        }
     }
 }
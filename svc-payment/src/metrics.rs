@@ -0,0 +1,113 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    db_wait: HistogramVec,
+    db_queries: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Handled HTTP requests"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time spent handling HTTP requests",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let db_wait = HistogramVec::new(
+            HistogramOpts::new(
+                "db_connection_wait_seconds",
+                "Time spent waiting for a pooled database connection",
+            ),
+            &["query"],
+        )
+        .unwrap();
+        let db_queries = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "Time spent running queries"),
+            &["query", "outcome"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(db_wait.clone())).unwrap();
+        registry.register(Box::new(db_queries.clone())).unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_duration,
+            db_wait,
+            db_queries,
+        }
+    }
+
+    pub fn db_wait(&self, query: &str, started: Instant) {
+        self.db_wait
+            .with_label_values(&[query])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    pub fn db_query(&self, query: &str, ok: bool, started: Instant) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.db_queries
+            .with_label_values(&[query, outcome])
+            .observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+/// Records count and latency of every request by route and status
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", MatchedPath::as_str)
+        .to_owned();
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let resp = next.run(req).await;
+
+    let status = resp.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    let m = metrics();
+    m.http_requests.with_label_values(&labels).inc();
+    m.http_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    resp
+}
@@ -0,0 +1,72 @@
+use diesel::{prelude::*, result::Error as DieselError};
+use uuid::Uuid;
+
+use crate::{
+    db::{self, DbError, DbPool},
+    dto::{Payment, PaymentStatus},
+    schema::payment,
+};
+
+#[derive(Debug, Clone)]
+pub struct PaymentRepository {
+    pool: DbPool,
+}
+
+impl PaymentRepository {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn get(&self, username: String, uid: Uuid) -> Result<Payment, DbError> {
+        db::run(&self.pool, "get_payment", move |conn| {
+            payment::table
+                .filter(payment::username.eq(username))
+                .filter(payment::payment_uid.eq(uid))
+                .select(Payment::as_select())
+                .get_result(conn)
+        })
+        .await
+    }
+
+    /// Payments of `username` with any of `uids`, unknown ones are skipped
+    pub async fn get_many(
+        &self,
+        username: String,
+        uids: Vec<Uuid>,
+    ) -> Result<Vec<Payment>, DbError> {
+        db::run(&self.pool, "get_payments", move |conn| {
+            payment::table
+                .filter(payment::username.eq(username))
+                .filter(payment::payment_uid.eq_any(uids))
+                .select(Payment::as_select())
+                .load(conn)
+        })
+        .await
+    }
+
+    /// Cancels the payment, a payment of another user is reported as not found
+    pub async fn cancel(&self, username: String, uid: Uuid) -> Result<(), DbError> {
+        db::run(&self.pool, "cancel_payment", move |conn| {
+            let updated = diesel::update(payment::table)
+                .filter(payment::username.eq(username))
+                .filter(payment::payment_uid.eq(uid))
+                .set(payment::status.eq(PaymentStatus::Canceled.to_string()))
+                .execute(conn)?;
+            if updated == 0 {
+                return Err(DieselError::NotFound);
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn create(&self, new: Payment) -> Result<Payment, DbError> {
+        db::run(&self.pool, "create_payment", move |conn| {
+            diesel::insert_into(payment::table)
+                .values(&new)
+                .returning(Payment::as_returning())
+                .get_result(conn)
+        })
+        .await
+    }
+}
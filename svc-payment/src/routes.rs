@@ -1,13 +1,28 @@
+use std::{collections::BTreeMap, sync::atomic::Ordering};
+
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
-    Json,
 };
-use diesel::{prelude::*, result::Error as DieselError};
 use uuid::Uuid;
 
-use crate::{dto::*, schema::payment, AppState};
+use crate::{
+    db,
+    dto::*,
+    error::{ApiError, ErrorResponse, Json, Path},
+    logger::{LogLevels, LogLevelsUpdate},
+    metrics::metrics,
+    AppState,
+};
+
+/// Name of the user the request is made for, required by all `/api` routes
+fn username(headers: &HeaderMap) -> Result<&str, ApiError> {
+    headers
+        .get("X-User-Name")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| ApiError::bad_request("X-User-Name header is required"))
+}
 
 #[utoipa::path(
     get,
@@ -20,6 +35,112 @@ pub async fn check_health() -> impl IntoResponse {
     StatusCode::OK
 }
 
+#[utoipa::path(
+    get,
+    path = "/manage/metrics",
+    responses(
+        (
+            status = OK,
+            description = "Метрики в формате Prometheus",
+            body = String,
+            content_type = "text/plain; version=0.0.4",
+        ),
+    )
+)]
+pub async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/log-levels",
+    responses(
+        (
+            status = OK,
+            description = "Текущие уровни логирования",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn get_log_levels(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.logging.levels())
+}
+
+#[utoipa::path(
+    put,
+    path = "/manage/log-levels",
+    request_body = LogLevelsUpdate,
+    responses(
+        (
+            status = OK,
+            description = "Уровни логирования изменены",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+        (status = BAD_REQUEST, description = "Неизвестный уровень логирования", body = ErrorResponse),
+        (status = UNAUTHORIZED, body = ErrorResponse, description = "Нужен токен администратора"),
+    )
+)]
+pub async fn put_log_levels(
+    State(state): State<AppState>,
+    Json(update): Json<LogLevelsUpdate>,
+) -> impl IntoResponse {
+    match state.logging.update(update) {
+        Ok(levels) => Json(levels).into_response(),
+        Err(errors) => ApiError::bad_request(errors.join("; ")).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/ready",
+    responses(
+        (
+            status = OK,
+            description = "Сервис готов обрабатывать запросы",
+            body = ReadinessResponse,
+            content_type = "application/json",
+        ),
+        (
+            status = SERVICE_UNAVAILABLE,
+            description = "База данных недоступна или миграции не применены",
+            body = ReadinessResponse,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn check_ready(State(state): State<AppState>) -> impl IntoResponse {
+    let database = ComponentHealth::probe(db::ping(&state.pool)).await;
+    let migrations = if database.status == HealthStatus::Down {
+        ComponentHealth::down(0.0, "database is unavailable")
+    } else if !state.ready.load(Ordering::Acquire) {
+        ComponentHealth::down(0.0, "migrations are not applied yet")
+    } else {
+        ComponentHealth::probe(async {
+            match db::has_pending_migrations(&state.pool).await {
+                Ok(false) => Ok(()),
+                Ok(true) => Err("some migrations are not applied".to_owned()),
+                Err(e) => Err(e.to_string()),
+            }
+        })
+        .await
+    };
+
+    let resp = ReadinessResponse::new(BTreeMap::from([
+        ("database".to_owned(), database),
+        ("migrations".to_owned(), migrations),
+    ]));
+    let status = match resp.status {
+        HealthStatus::Up => StatusCode::OK,
+        HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(resp))
+}
+
 #[utoipa::path(
     get,
     path = "/api/v1/payment/{paymentUid}",
@@ -30,26 +151,64 @@ pub async fn check_health() -> impl IntoResponse {
             body = Payment,
             content_type = "application/json",
         ),
+        (
+            status = NOT_FOUND,
+            description = "Оплата не найдена или принадлежит другому пользователю",
+            body = ErrorResponse,
+        ),
     ),
     params(
-        ("paymentUid", Path, description = "Идентификатор оплаты")
+        ("X-User-Name", Header, description = "Имя пользователя"),
+        ("paymentUid", Path, description = "Идентификатор оплаты"),
     ),
 )]
 pub async fn get_payment(
     State(state): State<AppState>,
     Path(uid): Path<Uuid>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-
-    let res = payment::table
-        .filter(payment::payment_uid.eq(uid))
-        .select(Payment::as_select())
-        .get_result::<Payment>(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+    let res = state.payments.get(username.to_owned(), uid).await?;
+
+    Ok(Json(res))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/payment/batch",
+    request_body = PaymentBatchRequest,
+    responses(
+        (
+            status = OK,
+            description = "Найденные оплаты, неизвестные идентификаторы пропускаются",
+            body = Vec<Payment>,
+            content_type = "application/json",
+        ),
+        (status = BAD_REQUEST, description = "Слишком много идентификаторов в запросе", body = ErrorResponse),
+    ),
+    params(
+        ("X-User-Name", Header, description = "Имя пользователя")
+    ),
+)]
+pub async fn get_payments(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<PaymentBatchRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+    if req.payment_uids.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::bad_request(format!(
+            "At most {MAX_BATCH_SIZE} payments can be requested at once"
+        )));
+    }
+    let res = if req.payment_uids.is_empty() {
+        Vec::new()
+    } else {
+        state
+            .payments
+            .get_many(username.to_owned(), req.payment_uids)
+            .await?
+    };
 
     Ok(Json(res))
 }
@@ -63,26 +222,24 @@ pub async fn get_payment(
             description = "Оплата отменена",
             content_type = "application/json",
         ),
+        (
+            status = NOT_FOUND,
+            description = "Оплата не найдена или принадлежит другому пользователю",
+            body = ErrorResponse,
+        ),
     ),
     params(
-        ("paymentUid", Path, description = "Идентификатор оплаты")
+        ("X-User-Name", Header, description = "Имя пользователя"),
+        ("paymentUid", Path, description = "Идентификатор оплаты"),
     ),
 )]
 pub async fn delete_payment(
     State(state): State<AppState>,
     Path(uid): Path<Uuid>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-
-    diesel::update(payment::table)
-        .filter(payment::payment_uid.eq(uid))
-        .set(payment::status.eq(PaymentStatus::Canceled.to_string()))
-        .execute(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+    state.payments.cancel(username.to_owned(), uid).await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -93,20 +250,20 @@ pub async fn delete_payment(
     responses(
         (status = CREATED, body = Payment, description = "Success")
     ),
+    params(
+        ("X-User-Name", Header, description = "Пользователь, которому принадлежит оплата")
+    ),
 )]
 pub async fn post_payment(
     State(state): State<AppState>,
+    headers: HeaderMap,
     Json(payment): Json<PaymentRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-
-    let payment = Payment::from(payment);
-    let created = diesel::insert_into(payment::table)
-        .values(&payment)
-        .returning(Payment::as_returning())
-        .get_result(conn)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+    let created = state
+        .payments
+        .create(payment.into_payment(username.to_owned()))
+        .await?;
 
     log::debug!("Created payment: {}", created.payment_uid);
 
@@ -7,5 +7,7 @@ diesel::table! {
         #[max_length = 20]
         status -> Varchar,
         price -> Int4,
+        #[max_length = 80]
+        username -> Varchar,
     }
 }
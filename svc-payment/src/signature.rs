@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::{config::SigningConfig, error::ApiError};
+
+pub const SIGNATURE: &str = "X-Signature";
+pub const TIMESTAMP: &str = "X-Signature-Timestamp";
+pub const NONCE: &str = "X-Signature-Nonce";
+
+/// Largest request body that is read to check the signature
+const BODY_LIMIT: usize = 1024 * 1024;
+
+/// Headers requests are authorized by, their values are signed too
+pub const SIGNED_HEADERS: [&str; 1] = ["X-User-Name"];
+
+/// What the gateway signs: method, path with query, signed headers, unix timestamp, nonce
+/// and body hash
+pub fn payload(
+    method: &str,
+    path: &str,
+    headers: &HeaderMap,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> String {
+    let signed: String = SIGNED_HEADERS
+        .iter()
+        .map(|name| {
+            let value = headers.get(*name).map_or(&b""[..], |v| v.as_bytes());
+            format!(
+                "{}:{}\n",
+                name.to_lowercase(),
+                String::from_utf8_lossy(value)
+            )
+        })
+        .collect();
+    format!(
+        "{method}\n{path}\n{signed}{timestamp}\n{nonce}\n{}",
+        hex::encode(Sha256::digest(body))
+    )
+}
+
+#[derive(Debug)]
+struct Inner {
+    secret: Vec<u8>,
+    max_skew: i64,
+    /// Nonces of accepted requests with the time they stop being valid
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+/// Checks HMAC-SHA256 signatures of requests from the gateway. A signature is accepted once
+/// and only while its timestamp is within `max_skew_secs` of the local clock
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    inner: Arc<Inner>,
+}
+
+impl Verifier {
+    /// Returns `None` if no secret is configured
+    pub fn new(config: &SigningConfig) -> Option<Self> {
+        if config.secret.is_empty() {
+            return None;
+        }
+        Some(Self {
+            inner: Arc::new(Inner {
+                secret: config.secret.as_bytes().to_vec(),
+                max_skew: config.max_skew_secs as i64,
+                seen: Mutex::default(),
+            }),
+        })
+    }
+
+    pub fn verify(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: i64,
+    ) -> Result<(), &'static str> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        let (Some(signature), Some(timestamp), Some(nonce)) =
+            (header(SIGNATURE), header(TIMESTAMP), header(NONCE))
+        else {
+            return Err("request is not signed");
+        };
+        let timestamp: i64 = timestamp
+            .parse()
+            .map_err(|_| "invalid signature timestamp")?;
+        if (now - timestamp).abs() > self.inner.max_skew {
+            return Err("signature has expired");
+        }
+        let signature = hex::decode(signature).map_err(|_| "invalid signature")?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.inner.secret).unwrap();
+        mac.update(payload(method, path, headers, timestamp, nonce, body).as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| "signature does not match")?;
+
+        let mut seen = self.inner.seen.lock().unwrap();
+        seen.retain(|_, expires| *expires >= now);
+        if seen
+            .insert(nonce.to_owned(), timestamp + self.inner.max_skew)
+            .is_some()
+        {
+            return Err("request was replayed");
+        }
+        Ok(())
+    }
+}
+
+/// Rejects `/api` requests that are not signed by the gateway with 401
+pub async fn verify(State(verifier): State<Verifier>, req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/api/") {
+        return next.run(req).await;
+    }
+
+    let (parts, body) = req.into_parts();
+    let Ok(body) = to_bytes(body, BODY_LIMIT).await else {
+        return ApiError::from(StatusCode::PAYLOAD_TOO_LARGE).into_response();
+    };
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    if let Err(e) = verifier.verify(
+        parts.method.as_str(),
+        path,
+        &parts.headers,
+        &body,
+        Utc::now().timestamp(),
+    ) {
+        log::warn!("Rejected {} {path}: {e}", parts.method);
+        return ApiError::new(StatusCode::UNAUTHORIZED, e).into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
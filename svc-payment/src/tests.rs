@@ -1,2 +1,476 @@
 #[test]
 fn hello_world() {}
+
+/// The global logger can only be installed once per process, so tests share it
+fn logging() -> crate::logger::Logging {
+    static LOGGING: std::sync::OnceLock<crate::logger::Logging> = std::sync::OnceLock::new();
+    LOGGING
+        .get_or_init(|| {
+            let mut config = crate::config::Config::default().log;
+            config.level = "off".to_owned();
+            crate::logger::init(&config)
+        })
+        .clone()
+}
+
+#[test]
+fn config_takes_database_url_from_env() {
+    use crate::config::{Config, ConfigError};
+
+    assert!(matches!(
+        Config::from_layers(None, |_| None),
+        Err(ConfigError::Invalid(_))
+    ));
+
+    let env = |name: &str| (name == "DATABASE_URL").then(|| "postgres://db/test".to_owned());
+    let config = Config::from_layers(Some("[server]\nworker_threads = 2"), env).unwrap();
+    assert_eq!(config.database.url, "postgres://db/test");
+    assert_eq!(config.server.worker_threads, 2);
+}
+
+#[test]
+fn printed_config_hides_secrets() {
+    use crate::config::Config;
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://program:test@db/payments".to_owned()),
+        "ADMIN_TOKEN" => Some("admin-token".to_owned()),
+        _ => None,
+    };
+    let printed = toml::to_string_pretty(&Config::from_layers(None, env).unwrap()).unwrap();
+    assert!(!printed.contains("program:test"));
+    assert!(!printed.contains("admin-token"));
+    assert!(printed.contains(r#"url = "***""#));
+    assert!(printed.contains(r#"token = "***""#));
+    // unset secrets are shown as such
+    assert!(printed.contains(r#"secret = """#));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn database_wait_does_not_block_runtime() {
+    use std::time::{Duration, Instant};
+
+    use axum::http::StatusCode;
+
+    use crate::{config::Config, db, error::ApiError};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        "DATABASE_ACQUIRE_TIMEOUT_MS" => Some("300".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+
+    let ticker = tokio::spawn(async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Instant::now()
+    });
+    let res = db::run(&pool, "test", |_| Ok(())).await;
+    let finished = Instant::now();
+
+    assert_eq!(
+        ApiError::from(res.unwrap_err()).status,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    // the single runtime thread kept running other tasks while the pool was waiting
+    assert!(ticker.await.unwrap() < finished);
+    assert!(crate::metrics::metrics()
+        .render()
+        .contains(r#"db_connection_wait_seconds_count{query="test"} 1"#));
+}
+
+#[tokio::test]
+async fn startup_retries_until_database_is_available() {
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        time::Duration,
+    };
+
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use tower::ServiceExt;
+
+    use crate::{config::Config, db};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        "DATABASE_ACQUIRE_TIMEOUT_MS" => Some("50".to_owned()),
+        "DATABASE_STARTUP_RETRY_INITIAL_MS" => Some("10".to_owned()),
+        "DATABASE_STARTUP_RETRY_MAX_MS" => Some("20".to_owned()),
+        _ => None,
+    };
+    let config = Config::from_layers(None, env).unwrap().database;
+    let pool = db::pool(&config);
+    let ready = Arc::new(AtomicBool::new(false));
+
+    let init = tokio::spawn(crate::init_db(pool.clone(), config, ready.clone()));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    // several attempts failed, but the task neither gave up nor panicked
+    assert!(!init.is_finished());
+    init.abort();
+
+    let resp = crate::app(pool, ready, logging(), None, crate::admin::Admin::default())
+        .await
+        .oneshot(Request::get("/manage/ready").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report["status"], "DOWN");
+    assert_eq!(report["components"]["database"]["status"], "DOWN");
+    assert!(report["components"]["database"]["latencyMs"].is_number());
+    assert_eq!(report["components"]["migrations"]["status"], "DOWN");
+}
+
+#[tokio::test]
+async fn request_id_is_echoed_or_generated() {
+    use axum::{body::Body, http::Request};
+    use std::sync::{atomic::AtomicBool, Arc};
+    use tower::ServiceExt;
+
+    use crate::{config::Config, db};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+    let app = crate::app(
+        pool,
+        Arc::new(AtomicBool::new(false)),
+        logging(),
+        None,
+        crate::admin::Admin::default(),
+    )
+    .await;
+
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::get("/manage/health")
+                .header("X-Request-Id", "req-42")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["X-Request-Id"], "req-42");
+
+    let resp = app
+        .oneshot(Request::get("/manage/health").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert!(!resp.headers()["X-Request-Id"].is_empty());
+}
+
+#[tokio::test]
+async fn payment_batch_size_is_limited() {
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use std::sync::{atomic::AtomicBool, Arc};
+    use tower::ServiceExt;
+
+    use crate::{
+        config::Config,
+        db,
+        dto::MAX_BATCH_SIZE,
+        error::{ErrorCode, ErrorResponse},
+    };
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+    let app = crate::app(
+        pool,
+        Arc::new(AtomicBool::new(false)),
+        logging(),
+        None,
+        crate::admin::Admin::default(),
+    )
+    .await;
+    let batch = |n: usize| {
+        let uids: Vec<_> = (0..n).map(|_| uuid::Uuid::new_v4()).collect();
+        Request::post("/api/v1/payment/batch")
+            .header("Content-Type", "application/json")
+            .header("X-User-Name", "Test Max")
+            .body(Body::from(
+                serde_json::json!({ "paymentUids": uids }).to_string(),
+            ))
+            .unwrap()
+    };
+
+    let mut request = batch(MAX_BATCH_SIZE + 1);
+    request
+        .headers_mut()
+        .insert("X-Request-Id", "batch-too-large".parse().unwrap());
+    let resp = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let error: ErrorResponse = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error.code, ErrorCode::BadRequest);
+    assert_eq!(
+        error.message,
+        format!("At most {MAX_BATCH_SIZE} payments can be requested at once")
+    );
+    assert_eq!(error.request_id.as_deref(), Some("batch-too-large"));
+
+    // payments are only looked up on behalf of their owner
+    let mut anonymous = batch(1);
+    anonymous.headers_mut().remove("X-User-Name");
+    let resp = app.clone().oneshot(anonymous).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // nothing to look up, the database is not queried
+    let resp = app.oneshot(batch(0)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"[]");
+}
+
+#[tokio::test]
+async fn malformed_requests_are_rejected_with_error_body() {
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+    use std::sync::{atomic::AtomicBool, Arc};
+    use tower::ServiceExt;
+
+    use crate::{
+        config::Config,
+        db,
+        error::{ErrorCode, ErrorResponse},
+    };
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+    let app = crate::app(
+        pool,
+        Arc::new(AtomicBool::new(false)),
+        logging(),
+        None,
+        crate::admin::Admin::default(),
+    )
+    .await;
+    let rejected = |req: Request<Body>| {
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: ErrorResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.code, ErrorCode::BadRequest);
+        }
+    };
+
+    rejected(
+        Request::post("/api/v1/payment/batch")
+            .header("X-User-Name", "Test Max")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"paymentUids\":"))
+            .unwrap(),
+    )
+    .await;
+    rejected(
+        Request::get("/api/v1/payment/not-a-uuid")
+            .header("X-User-Name", "Test Max")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+}
+
+#[test]
+fn unsigned_and_replayed_requests_are_rejected() {
+    use axum::http::{HeaderMap, HeaderValue};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use crate::{
+        config::Config,
+        signature::{self, Verifier},
+    };
+
+    let mut config = Config::default().signing;
+    assert!(Verifier::new(&config).is_none());
+    config.secret = "internal-secret".to_owned();
+    let verifier = Verifier::new(&config).unwrap();
+
+    let now = 1_700_000_000;
+    let sign = |secret: &str, timestamp: i64, nonce: &str, body: &[u8]| {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-User-Name", HeaderValue::from_static("Test Max"));
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(
+            signature::payload("PUT", "/api/v1/x?y=1", &headers, timestamp, nonce, body).as_bytes(),
+        );
+        headers.insert(
+            signature::SIGNATURE,
+            HeaderValue::from_str(&hex::encode(mac.finalize().into_bytes())).unwrap(),
+        );
+        headers.insert(signature::TIMESTAMP, HeaderValue::from(timestamp));
+        headers.insert(signature::NONCE, HeaderValue::from_str(nonce).unwrap());
+        headers
+    };
+    let verify = |headers: &HeaderMap, body: &[u8]| {
+        verifier.verify("PUT", "/api/v1/x?y=1", headers, body, now)
+    };
+
+    let headers = sign("internal-secret", now - 10, "n1", b"{}");
+    assert_eq!(verify(&headers, b"{}"), Ok(()));
+    assert!(verify(&headers, b"{}").is_err());
+
+    assert!(verify(&HeaderMap::new(), b"{}").is_err());
+    assert!(verify(&sign("internal-secret", now, "n2", b"{}"), b"{\"a\":1}").is_err());
+    assert!(verify(&sign("other-secret", now, "n3", b"{}"), b"{}").is_err());
+    assert!(verify(&sign("internal-secret", now - 301, "n4", b"{}"), b"{}").is_err());
+
+    // the user a request is made for can't be changed
+    let mut headers = sign("internal-secret", now, "n5", b"{}");
+    headers.insert("X-User-Name", HeaderValue::from_static("Other"));
+    assert!(verify(&headers, b"{}").is_err());
+    headers.remove("X-User-Name");
+    assert!(verify(&headers, b"{}").is_err());
+}
+
+/// Runs against a scratch database created through `TEST_DATABASE_URL`, skipped if unset
+#[tokio::test]
+async fn payments_from_before_owners_are_unavailable() {
+    use diesel::{result::Error as DieselError, Connection, PgConnection, RunQueryDsl};
+    use diesel_migrations::MigrationHarness;
+    use uuid::Uuid;
+
+    use crate::{
+        config::Config,
+        db::{self, DbError},
+        dto::PaymentStatus,
+        repository::PaymentRepository,
+    };
+
+    let Ok(admin_url) = std::env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set, skipping");
+        return;
+    };
+    let name = format!("payment_migration_{}", Uuid::new_v4().simple());
+    let mut admin = PgConnection::establish(&admin_url).unwrap();
+    diesel::sql_query(format!("CREATE DATABASE {name}"))
+        .execute(&mut admin)
+        .unwrap();
+    let (base, _) = admin_url.rsplit_once('/').unwrap();
+    let url = format!("{base}/{name}");
+
+    // a payment made before the owner migration
+    let legacy = Uuid::new_v4();
+    let mut conn = PgConnection::establish(&url).unwrap();
+    conn.run_next_migration(crate::MIGRATIONS).unwrap();
+    diesel::sql_query(format!(
+        "INSERT INTO payment (payment_uid, status, price) VALUES ('{legacy}', 'PAID', 9000)"
+    ))
+    .execute(&mut conn)
+    .unwrap();
+    conn.run_pending_migrations(crate::MIGRATIONS).unwrap();
+    drop(conn);
+
+    let env = |key: &str| (key == "DATABASE_URL").then(|| url.clone());
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+    let payments = PaymentRepository::new(pool.clone());
+    assert!(matches!(
+        payments.get("Test Max".to_owned(), legacy).await,
+        Err(DbError::Query(DieselError::NotFound))
+    ));
+    assert!(payments
+        .get_many("Test Max".to_owned(), vec![legacy])
+        .await
+        .unwrap()
+        .is_empty());
+    assert!(matches!(
+        payments.cancel("Test Max".to_owned(), legacy).await,
+        Err(DbError::Query(DieselError::NotFound))
+    ));
+
+    // the backfill gives it the owner of its reservation, canceling did not change it
+    let mut conn = PgConnection::establish(&url).unwrap();
+    diesel::sql_query(format!(
+        "UPDATE payment SET username = 'Test Max' WHERE payment_uid = '{legacy}'"
+    ))
+    .execute(&mut conn)
+    .unwrap();
+    drop(conn);
+    let payment = payments.get("Test Max".to_owned(), legacy).await.unwrap();
+    assert_eq!(payment.price, 9000);
+    assert_eq!(payment.status, PaymentStatus::Paid.to_string());
+    assert!(payments.get("Other".to_owned(), legacy).await.is_err());
+
+    drop(payments);
+    drop(pool);
+    diesel::sql_query(format!("DROP DATABASE {name} WITH (FORCE)"))
+        .execute(&mut admin)
+        .unwrap();
+}
+
+#[tokio::test]
+async fn log_levels_change_requires_admin_token() {
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use std::sync::{atomic::AtomicBool, Arc};
+    use tower::ServiceExt;
+
+    use crate::{admin::Admin, config::Config, db};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        "ADMIN_TOKEN" => Some("admin-token".to_owned()),
+        _ => None,
+    };
+    let config = Config::from_layers(None, env).unwrap();
+    let app = crate::app(
+        db::pool(&config.database),
+        Arc::new(AtomicBool::new(false)),
+        logging(),
+        None,
+        Admin::new(&config.admin),
+    )
+    .await;
+    let put = |token: Option<&str>| {
+        let mut req = Request::put("/manage/log-levels").header("Content-Type", "application/json");
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        req.body(Body::from("{}")).unwrap()
+    };
+
+    for token in [None, Some("user-token")] {
+        let resp = app.clone().oneshot(put(token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = app.clone().oneshot(put(Some("admin-token"))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    // reads stay open
+    let resp = app
+        .oneshot(
+            Request::get("/manage/log-levels")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
@@ -0,0 +1,122 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+pub const REQUEST_ID: &str = "X-Request-Id";
+const TRACEPARENT: &str = "traceparent";
+
+/// Request id and W3C trace context of the request being handled
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub request_id: String,
+    pub trace_id: String,
+    /// Span of this service
+    pub span_id: String,
+}
+
+impl TraceContext {
+    /// Continues the incoming trace, or starts a new one if the values are missing or malformed
+    pub fn incoming(request_id: Option<&str>, traceparent: Option<&str>) -> Self {
+        let request_id = request_id
+            .filter(|id| is_valid_request_id(id))
+            .map_or_else(|| Uuid::new_v4().to_string(), str::to_owned);
+        let trace_id = traceparent
+            .and_then(parse_traceparent)
+            .map_or_else(|| Uuid::new_v4().simple().to_string(), |(id, _)| id);
+        Self {
+            request_id,
+            trace_id,
+            span_id: Uuid::new_v4().simple().to_string()[..16].to_owned(),
+        }
+    }
+
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        Self::incoming(get(REQUEST_ID), get(TRACEPARENT))
+    }
+}
+
+/// Id of the request being handled, taken from the log context set by [`scope`]
+pub fn current_request_id() -> Option<String> {
+    log_mdc::get("request_id", |id| id.map(str::to_owned))
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Returns trace id and flags of a valid `traceparent`
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    // later versions may append fields, version 00 has exactly four
+    if !is_hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if !is_hex(trace_id, 32)
+        || !is_hex(parent_id, 16)
+        || !is_hex(flags, 2)
+        || trace_id.bytes().all(|b| b == b'0')
+        || parent_id.bytes().all(|b| b == b'0')
+    {
+        return None;
+    }
+    Some((trace_id.to_owned(), flags.to_owned()))
+}
+
+/// Runs `f` with `ctx` as the current context. Log records written meanwhile carry its ids
+pub fn scope<F: Future>(ctx: TraceContext, f: F) -> Traced<F> {
+    Traced {
+        ctx,
+        inner: Box::pin(f),
+    }
+}
+
+pub struct Traced<F> {
+    ctx: TraceContext,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        // the task may be polled on any worker thread, so the log context is set per poll
+        let _mdc = log_mdc::extend_scoped([
+            ("request_id", this.ctx.request_id.as_str()),
+            ("trace_id", this.ctx.trace_id.as_str()),
+            ("span_id", this.ctx.span_id.as_str()),
+        ]);
+        this.inner.as_mut().poll(cx)
+    }
+}
+
+/// Accepts or generates `X-Request-Id` and `traceparent` and echoes the request id back
+pub async fn propagate(req: Request, next: Next) -> Response {
+    let ctx = TraceContext::from_headers(req.headers());
+    let request_id = HeaderValue::from_str(&ctx.request_id);
+
+    let mut resp = scope(ctx, next.run(req)).await;
+    if let Ok(id) = request_id {
+        resp.headers_mut().insert(REQUEST_ID, id);
+    }
+    resp
+}
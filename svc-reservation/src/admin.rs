@@ -0,0 +1,57 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config::AdminConfig, error::ApiError};
+
+/// Checks the admin token of management requests
+#[derive(Debug, Clone, Default)]
+pub struct Admin {
+    /// Hash of the token, so the comparison time does not depend on where they differ
+    token: Option<[u8; 32]>,
+}
+
+impl Admin {
+    /// Without a token every request is refused
+    pub fn new(config: &AdminConfig) -> Self {
+        Self {
+            token: (!config.token.is_empty()).then(|| Sha256::digest(&config.token).into()),
+        }
+    }
+
+    pub fn allows(&self, token: &str) -> bool {
+        self.token
+            .is_some_and(|t| t == <[u8; 32]>::from(Sha256::digest(token)))
+    }
+}
+
+/// Requires `Authorization: Bearer <admin token>` on `/manage` requests that change anything.
+/// Reads stay open for probes and scrapers
+pub async fn authorize(State(admin): State<Admin>, req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/manage/") || req.method().is_safe() {
+        return next.run(req).await;
+    }
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if token.is_some_and(|t| admin.allows(t)) {
+        return next.run(req).await;
+    }
+    log::warn!(
+        "Rejected {} {}: admin token is missing or invalid",
+        req.method(),
+        req.uri().path()
+    );
+    (
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        ApiError::new(StatusCode::UNAUTHORIZED, "Admin token is required"),
+    )
+        .into_response()
+}
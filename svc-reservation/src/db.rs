@@ -0,0 +1,89 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool, PoolError},
+    result::Error as DieselError,
+    PgConnection, RunQueryDsl,
+};
+use diesel_migrations::MigrationHarness;
+use tokio::task::JoinError;
+
+use crate::{config::DatabaseConfig, metrics::metrics};
+
+pub type DbPool = Pool<ConnectionManager<PgConnection>>;
+
+/// Builds the pool without waiting for connections, so the service starts even if the
+/// database is not up yet
+pub fn pool(config: &DatabaseConfig) -> DbPool {
+    Pool::builder()
+        .max_size(config.pool_size)
+        .min_idle(Some(config.min_idle))
+        .connection_timeout(Duration::from_millis(config.acquire_timeout_ms))
+        .idle_timeout(Some(Duration::from_millis(config.idle_timeout_ms)))
+        .test_on_check_out(config.health_check)
+        .build_unchecked(ConnectionManager::new(&config.url))
+}
+
+/// Runs `f` with a pooled connection on the blocking thread pool. Neither waiting for a
+/// connection nor the query itself hold up the async workers. Timings are recorded under
+/// `query`
+pub async fn run<T, F>(pool: &DbPool, query: &'static str, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, DieselError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let started = Instant::now();
+        let conn = pool.get();
+        metrics().db_wait(query, started);
+        let conn = &mut conn.map_err(DbError::Unavailable)?;
+
+        let started = Instant::now();
+        let res = f(conn);
+        // a missing row is an answer, not a failure
+        let ok = matches!(res, Ok(_) | Err(DieselError::NotFound));
+        metrics().db_query(query, ok, started);
+        res.map_err(DbError::Query)
+    })
+    .await
+    .map_err(DbError::Task)?
+}
+
+/// Cheap round trip to the database, used by readiness checks
+pub async fn ping(pool: &DbPool) -> Result<(), DbError> {
+    run(pool, "ping", |conn| {
+        diesel::sql_query("SELECT 1").execute(conn).map(|_| ())
+    })
+    .await
+}
+
+/// Whether some of the embedded migrations are not applied to the database
+pub async fn has_pending_migrations(pool: &DbPool) -> Result<bool, DbError> {
+    run(pool, "pending_migrations", |conn| {
+        conn.has_pending_migration(crate::MIGRATIONS)
+            .map_err(DieselError::QueryBuilderError)
+    })
+    .await
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    /// No connection became available within `acquire_timeout_ms`
+    Unavailable(PoolError),
+    Query(DieselError),
+    Task(JoinError),
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unavailable(e) => write!(f, "failed to get database connection: {e}"),
+            Self::Query(e) => write!(f, "query failed: {e}"),
+            Self::Task(e) => write!(f, "database task failed: {e}"),
+        }
+    }
+}
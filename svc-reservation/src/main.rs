@@ -1,19 +1,39 @@
-use std::env;
+use std::{
+    env, process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use diesel::prelude::*;
+use admin::Admin;
+use config::{Args, Config, DatabaseConfig, ValidationConfig};
+use db::DbPool;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use logger::Logging;
+use repository::ReservationRepository;
+use signature::Verifier;
 use tokio::net::TcpListener;
 use utoipa::OpenApi;
 use utoipa_axum::{router::OpenApiRouter, routes};
 use utoipa_swagger_ui::SwaggerUi;
 
+mod admin;
+mod config;
+mod db;
 mod db_dto;
 mod diesel_paginate;
+mod error;
 mod logger;
+mod metrics;
+mod repository;
 mod request_dto;
 mod response_dto;
 mod routes;
 mod schema;
+mod signature;
+mod trace;
 
 #[cfg(test)]
 mod tests;
@@ -22,6 +42,10 @@ mod tests;
 #[openapi(
     paths(
         routes::check_health,
+        routes::check_ready,
+        routes::get_metrics,
+        routes::get_log_levels,
+        routes::put_log_levels,
         routes::get_hotels,
         routes::get_hotel,
         routes::get_reservations,
@@ -36,58 +60,152 @@ mod tests;
         response_dto::Reservation,
         response_dto::ReservationStatus,
         response_dto::ReservationWithHotel,
+        response_dto::ReadinessResponse,
+        logger::LogLevels,
+        logger::LogLevelsUpdate,
+        response_dto::ComponentHealth,
+        response_dto::HealthStatus,
         request_dto::ReservationPath,
         request_dto::ReservationRequest,
+        error::ErrorResponse,
+        error::ErrorCode,
+        error::FieldError,
     ))
 )]
 struct ApiDoc;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
-pub const SERVICE_ENDPOINT: &str = "0.0.0.0:8070";
 
 #[derive(Debug, Clone)]
 struct AppState {
-    database_url: String,
+    pool: DbPool,
+    /// Set once the database is reachable and migrations are applied
+    ready: Arc<AtomicBool>,
+    logging: Logging,
+    reservations: ReservationRepository,
+    validation: ValidationConfig,
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 4)]
-async fn main() {
-    let database_url =
-        env::var("DATABASE_URL").expect("DATABASE_URL environment variable was not specified");
+fn main() {
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\nUsage: bmstu-rsoi-lab2-reservation [--config <path>] [--print-config]");
+        process::exit(2)
+    });
+    let config = Config::load(args.config.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {e}");
+        process::exit(1)
+    });
+    if args.print_config {
+        print!("{}", toml::to_string_pretty(&config).unwrap());
+        return;
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.server.worker_threads)
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run(config));
+}
 
-    let _logger_handler = logger::init();
+async fn run(config: Config) {
+    let logging = logger::init(&config.log);
     log::debug!("Logger initialized. Hello, world!");
 
-    let app = app(database_url).await;
+    let pool = db::pool(&config.database);
+    let ready = Arc::new(AtomicBool::new(false));
+    tokio::spawn(init_db(
+        pool.clone(),
+        config.database.clone(),
+        ready.clone(),
+    ));
+    let app = app(
+        pool,
+        ready,
+        logging,
+        Verifier::new(&config.signing),
+        Admin::new(&config.admin),
+        config.validation.clone(),
+    )
+    .await;
 
-    log::info!("Listening on {}", SERVICE_ENDPOINT);
-    let listener = TcpListener::bind(SERVICE_ENDPOINT).await.unwrap();
+    log::info!("Listening on {}", config.server.bind);
+    let listener = TcpListener::bind(&config.server.bind).await.unwrap();
     axum::serve(listener, app.into_make_service())
         .await
         .unwrap();
 }
 
-async fn app(database_url: String) -> axum::Router {
-    init_db(database_url.as_str());
-
+async fn app(
+    pool: DbPool,
+    ready: Arc<AtomicBool>,
+    logging: Logging,
+    verifier: Option<Verifier>,
+    admin: Admin,
+    validation: ValidationConfig,
+) -> axum::Router {
     let swagger = SwaggerUi::new("/swagger-ui").url("/openapi.json", ApiDoc::openapi());
-    let state = AppState { database_url };
+    let state = AppState {
+        reservations: ReservationRepository::new(pool.clone()),
+        ready,
+        logging,
+        pool,
+        validation,
+    };
     let app = OpenApiRouter::with_openapi(ApiDoc::openapi())
         .routes(routes!(routes::check_health))
+        .routes(routes!(routes::check_ready))
+        .routes(routes!(routes::get_metrics))
+        .routes(routes!(routes::get_log_levels, routes::put_log_levels))
         .routes(routes!(routes::get_hotels))
         .routes(routes!(routes::get_hotel))
         .routes(routes!(routes::post_reservation, routes::get_reservations))
         .routes(routes!(routes::get_reservation, routes::delete_reservation))
         .with_state(state);
+    let app = match verifier {
+        Some(verifier) => app.layer(axum::middleware::from_fn_with_state(
+            verifier,
+            signature::verify,
+        )),
+        None => app,
+    };
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(
+            admin,
+            admin::authorize,
+        ))
+        .layer(axum::middleware::from_fn(metrics::track))
+        .layer(axum::middleware::from_fn(trace::propagate));
 
     axum::Router::from(app).merge(swagger)
 }
 
-fn init_db(database_url: &str) {
-    let conn = &mut PgConnection::establish(database_url)
-        .expect("Failed to establish connection to database");
-    let result = conn.run_pending_migrations(MIGRATIONS);
-    if let Err(e) = result {
-        panic!("Failed to initialize DB: {e}");
+/// Keeps connecting and applying migrations until both succeed, so the service can start
+/// before the database does
+async fn init_db(pool: DbPool, config: DatabaseConfig, ready: Arc<AtomicBool>) {
+    let mut backoff = Duration::from_millis(config.startup_retry_initial_ms);
+    loop {
+        let pool = pool.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            let conn = &mut pool.get().map_err(|e| e.to_string())?;
+            conn.run_pending_migrations(MIGRATIONS)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+        match res {
+            Ok(()) => {
+                log::info!("Database is ready");
+                ready.store(true, Ordering::Release);
+                return;
+            }
+            Err(e) => {
+                log::warn!("Failed to initialize DB, retrying in {backoff:?}: {e}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_millis(config.startup_retry_max_ms));
+            }
+        }
     }
 }
@@ -0,0 +1,106 @@
+use diesel::prelude::*;
+use uuid::Uuid;
+
+use crate::{
+    db::{self, DbError, DbPool},
+    db_dto,
+    diesel_paginate::*,
+    request_dto,
+    response_dto::ReservationStatus,
+    schema::{hotels, reservation},
+};
+
+#[derive(Debug, Clone)]
+pub struct ReservationRepository {
+    pool: DbPool,
+}
+
+impl ReservationRepository {
+    pub fn new(pool: DbPool) -> Self {
+        Self { pool }
+    }
+
+    /// Returns the page of hotels and the total number of pages
+    pub async fn hotels(&self, page: i64, size: i64) -> Result<(Vec<db_dto::Hotel>, i64), DbError> {
+        db::run(&self.pool, "get_hotels", move |conn| {
+            hotels::table
+                .order(hotels::name)
+                .select(db_dto::Hotel::as_select())
+                .paginate(page)
+                .per_page(size)
+                .load_and_count_pages(conn)
+        })
+        .await
+    }
+
+    pub async fn hotel(&self, uid: Uuid) -> Result<db_dto::Hotel, DbError> {
+        db::run(&self.pool, "get_hotel", move |conn| {
+            hotels::table
+                .filter(hotels::hotel_uid.eq(uid))
+                .select(db_dto::Hotel::as_select())
+                .get_result(conn)
+        })
+        .await
+    }
+
+    pub async fn reservations(
+        &self,
+        username: String,
+    ) -> Result<Vec<(db_dto::Reservation, db_dto::Hotel)>, DbError> {
+        db::run(&self.pool, "get_reservations", move |conn| {
+            reservation::table
+                .filter(reservation::username.eq(username))
+                .inner_join(hotels::table)
+                .select((db_dto::Reservation::as_select(), db_dto::Hotel::as_select()))
+                .load(conn)
+        })
+        .await
+    }
+
+    pub async fn reservation(
+        &self,
+        username: String,
+        uid: Uuid,
+    ) -> Result<(db_dto::Reservation, db_dto::Hotel), DbError> {
+        db::run(&self.pool, "get_reservation", move |conn| {
+            reservation::table
+                .filter(reservation::username.eq(username))
+                .filter(reservation::reservation_uid.eq(uid))
+                .inner_join(hotels::table)
+                .select((db_dto::Reservation::as_select(), db_dto::Hotel::as_select()))
+                .get_result(conn)
+        })
+        .await
+    }
+
+    pub async fn cancel(&self, username: String, uid: Uuid) -> Result<(), DbError> {
+        db::run(&self.pool, "cancel_reservation", move |conn| {
+            diesel::update(reservation::table)
+                .filter(reservation::username.eq(username))
+                .filter(reservation::reservation_uid.eq(uid))
+                .set(reservation::status.eq(ReservationStatus::Canceled.to_string()))
+                .execute(conn)
+        })
+        .await?;
+        Ok(())
+    }
+
+    pub async fn create(
+        &self,
+        username: String,
+        request: request_dto::ReservationRequest,
+    ) -> Result<db_dto::Reservation, DbError> {
+        db::run(&self.pool, "create_reservation", move |conn| {
+            let hotel_id = hotels::table
+                .filter(hotels::hotel_uid.eq(request.hotel_uid))
+                .select(hotels::id)
+                .get_result(conn)?;
+
+            diesel::insert_into(reservation::table)
+                .values(&request.into_db_dto(username, Some(hotel_id)))
+                .returning(db_dto::Reservation::as_returning())
+                .get_result(conn)
+        })
+        .await
+    }
+}
@@ -1,29 +1,79 @@
-use chrono::DateTime;
+use chrono::{DateTime, NaiveDate};
 use serde::Deserialize;
 use utoipa::ToSchema;
 use uuid::Uuid;
+use validator::{Validate, ValidationError};
 
 use crate::{db_dto, response_dto::ReservationStatus};
 
-#[derive(Deserialize)]
+/// Largest page of hotels that can be requested
+pub const MAX_PAGE_SIZE: usize = 100;
+/// Longest stay a reservation can be made for
+pub const MAX_STAY_DAYS: i64 = 30;
+
+#[derive(Deserialize, Validate)]
 pub struct Pagination {
+    #[validate(range(min = 1))]
     pub page: usize,
+    #[validate(range(min = 1, max = MAX_PAGE_SIZE))]
     pub size: usize,
 }
 
-#[derive(Deserialize, ToSchema)]
+/// Dates are checked against the earliest day a reservation may start on
+#[derive(Deserialize, ToSchema, Validate)]
 #[serde(rename_all = "camelCase")]
+#[validate(
+    context = NaiveDate,
+    schema(function = "validate_stay", skip_on_field_errors = false)
+)]
 pub struct ReservationRequest {
+    /// Идентификатор брони, выбранный вызывающей стороной. Если не указан, создаётся новый
+    #[serde(default)]
+    pub reservation_uid: Option<Uuid>,
     pub hotel_uid: Uuid,
     pub payment_uid: Uuid,
+    #[validate(custom(function = "validate_start", use_context))]
     pub start_date: Option<DateTime<chrono::Local>>,
     pub end_date: Option<DateTime<chrono::Local>>,
 }
 
+fn validate_start(
+    start: &DateTime<chrono::Local>,
+    earliest: &NaiveDate,
+) -> Result<(), ValidationError> {
+    if start.date_naive() < *earliest {
+        return Err(
+            ValidationError::new("past_date").with_message("must not be in the past".into())
+        );
+    }
+    Ok(())
+}
+
+/// Checks the order and length of the stay. The error is about `endDate`, which is named in
+/// its `field` param
+fn validate_stay(req: &ReservationRequest) -> Result<(), ValidationError> {
+    let (Some(start), Some(end)) = (req.start_date, req.end_date) else {
+        return Ok(());
+    };
+    let (code, message) = if end <= start {
+        ("date_order", "must be after startDate".to_owned())
+    } else if (end.date_naive() - start.date_naive()).num_days() > MAX_STAY_DAYS {
+        (
+            "stay_too_long",
+            format!("must be at most {MAX_STAY_DAYS} days after startDate"),
+        )
+    } else {
+        return Ok(());
+    };
+    let mut error = ValidationError::new(code).with_message(message.into());
+    error.add_param("field".into(), &"endDate");
+    Err(error)
+}
+
 impl ReservationRequest {
     pub fn into_db_dto(self, username: String, hotel_id: Option<i32>) -> db_dto::Reservation {
         db_dto::Reservation {
-            reservation_uid: Uuid::new_v4(),
+            reservation_uid: self.reservation_uid.unwrap_or_else(Uuid::new_v4),
             username,
             payment_uid: self.payment_uid,
             hotel_id,
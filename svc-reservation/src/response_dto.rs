@@ -1,4 +1,4 @@
-use std::{fmt::Display, str::FromStr};
+use std::{collections::BTreeMap, fmt::Display, future::Future, str::FromStr, time::Instant};
 
 use chrono::DateTime;
 use serde::{Deserialize, Serialize};
@@ -150,3 +150,64 @@ impl FromStr for ReservationStatus {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+/// Результат проверки одной зависимости сервиса
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    /// Длительность проверки в миллисекундах
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ComponentHealth {
+    /// Runs `check` and records how long it took
+    pub async fn probe<E: Display>(check: impl Future<Output = Result<(), E>>) -> Self {
+        let started = Instant::now();
+        let res = check.await;
+        let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+        match res {
+            Ok(()) => Self {
+                status: HealthStatus::Up,
+                latency_ms,
+                error: None,
+            },
+            Err(e) => Self::down(latency_ms, e),
+        }
+    }
+
+    pub fn down(latency_ms: f64, error: impl Display) -> Self {
+        Self {
+            status: HealthStatus::Down,
+            latency_ms,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ReadinessResponse {
+    /// `UP`, только если готовы все компоненты
+    pub status: HealthStatus,
+    pub components: BTreeMap<String, ComponentHealth>,
+}
+
+impl ReadinessResponse {
+    pub fn new(components: BTreeMap<String, ComponentHealth>) -> Self {
+        let status = if components.values().all(|c| c.status == HealthStatus::Up) {
+            HealthStatus::Up
+        } else {
+            HealthStatus::Down
+        };
+        Self { status, components }
+    }
+}
@@ -1,20 +1,29 @@
+use std::{collections::BTreeMap, sync::atomic::Ordering};
+
 use axum::{
-    extract::{Path, Query, State},
-    http::{HeaderMap, StatusCode},
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
     response::IntoResponse,
-    Json,
 };
-use diesel::{prelude::*, result::Error as DieselError};
 use uuid::Uuid;
+use validator::{Validate, ValidateArgs};
 
 use crate::{
-    db_dto,
-    diesel_paginate::*,
-    request_dto, response_dto,
-    schema::{hotels, reservation},
-    AppState,
+    db,
+    error::{ApiError, ErrorResponse, Json, Path, Query},
+    logger::{LogLevels, LogLevelsUpdate},
+    metrics::metrics,
+    request_dto, response_dto, AppState,
 };
 
+/// Name of the user the request is made for, required by all `/api` routes
+fn username(headers: &HeaderMap) -> Result<&str, ApiError> {
+    headers
+        .get("X-User-Name")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| ApiError::bad_request("X-User-Name header is required"))
+}
+
 #[utoipa::path(
     get,
     path = "/manage/health",
@@ -26,6 +35,112 @@ pub async fn check_health() -> impl IntoResponse {
     StatusCode::OK
 }
 
+#[utoipa::path(
+    get,
+    path = "/manage/metrics",
+    responses(
+        (
+            status = OK,
+            description = "Метрики в формате Prometheus",
+            body = String,
+            content_type = "text/plain; version=0.0.4",
+        ),
+    )
+)]
+pub async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/log-levels",
+    responses(
+        (
+            status = OK,
+            description = "Текущие уровни логирования",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn get_log_levels(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.logging.levels())
+}
+
+#[utoipa::path(
+    put,
+    path = "/manage/log-levels",
+    request_body = LogLevelsUpdate,
+    responses(
+        (
+            status = OK,
+            description = "Уровни логирования изменены",
+            body = LogLevels,
+            content_type = "application/json",
+        ),
+        (status = BAD_REQUEST, description = "Неизвестный уровень логирования", body = ErrorResponse),
+        (status = UNAUTHORIZED, body = ErrorResponse, description = "Нужен токен администратора"),
+    )
+)]
+pub async fn put_log_levels(
+    State(state): State<AppState>,
+    Json(update): Json<LogLevelsUpdate>,
+) -> impl IntoResponse {
+    match state.logging.update(update) {
+        Ok(levels) => Json(levels).into_response(),
+        Err(errors) => ApiError::bad_request(errors.join("; ")).into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/manage/ready",
+    responses(
+        (
+            status = OK,
+            description = "Сервис готов обрабатывать запросы",
+            body = response_dto::ReadinessResponse,
+            content_type = "application/json",
+        ),
+        (
+            status = SERVICE_UNAVAILABLE,
+            description = "База данных недоступна или миграции не применены",
+            body = response_dto::ReadinessResponse,
+            content_type = "application/json",
+        ),
+    )
+)]
+pub async fn check_ready(State(state): State<AppState>) -> impl IntoResponse {
+    let database = response_dto::ComponentHealth::probe(db::ping(&state.pool)).await;
+    let migrations = if database.status == response_dto::HealthStatus::Down {
+        response_dto::ComponentHealth::down(0.0, "database is unavailable")
+    } else if !state.ready.load(Ordering::Acquire) {
+        response_dto::ComponentHealth::down(0.0, "migrations are not applied yet")
+    } else {
+        response_dto::ComponentHealth::probe(async {
+            match db::has_pending_migrations(&state.pool).await {
+                Ok(false) => Ok(()),
+                Ok(true) => Err("some migrations are not applied".to_owned()),
+                Err(e) => Err(e.to_string()),
+            }
+        })
+        .await
+    };
+
+    let resp = response_dto::ReadinessResponse::new(BTreeMap::from([
+        ("database".to_owned(), database),
+        ("migrations".to_owned(), migrations),
+    ]));
+    let status = match resp.status {
+        response_dto::HealthStatus::Up => StatusCode::OK,
+        response_dto::HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(resp))
+}
+
 #[utoipa::path(
     get,
     path = "/api/v1/hotels",
@@ -36,6 +151,7 @@ pub async fn check_health() -> impl IntoResponse {
             body = response_dto::HotelList,
             content_type = "application/json",
         ),
+        (status = BAD_REQUEST, description = "Недопустимые параметры страницы", body = ErrorResponse),
     ),
     params(
         ("page", Query, description="Количество страниц"),
@@ -46,14 +162,13 @@ pub async fn get_hotels(
     State(state): State<AppState>,
     Query(pagination): Query<request_dto::Pagination>,
 ) -> impl IntoResponse {
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-    let res = hotels::table
-        .order(hotels::name)
-        .select(db_dto::Hotel::as_select())
-        .paginate(pagination.page as i64)
-        .per_page(pagination.size as i64)
-        .load_and_count_pages(conn);
+    if let Err(e) = pagination.validate() {
+        return ApiError::from(e).into_response();
+    }
+    let res = state
+        .reservations
+        .hotels(pagination.page as i64, pagination.size as i64)
+        .await;
 
     match res {
         Ok((hotels, count)) => (
@@ -66,7 +181,7 @@ pub async fn get_hotels(
             }),
         )
             .into_response(),
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Err(e) => ApiError::from(e).into_response(),
     }
 }
 
@@ -80,6 +195,7 @@ pub async fn get_hotels(
             body = response_dto::Hotel,
             content_type = "application/json",
         ),
+        (status = NOT_FOUND, description = "Отель не найден", body = ErrorResponse),
     ),
     params(
         ("hotelid", Path, description="ID отеля"),
@@ -88,17 +204,8 @@ pub async fn get_hotels(
 pub async fn get_hotel(
     State(state): State<AppState>,
     Path(uid): Path<Uuid>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-    let res = hotels::table
-        .filter(hotels::hotel_uid.eq(uid))
-        .select(db_dto::Hotel::as_select())
-        .get_result(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
+) -> Result<impl IntoResponse, ApiError> {
+    let res = state.reservations.hotel(uid).await?;
 
     Ok(Json(response_dto::Hotel::from(res)))
 }
@@ -122,18 +229,12 @@ pub async fn get_reservations(
     State(state): State<AppState>,
     headers: HeaderMap,
 ) -> impl IntoResponse {
-    let user_name = match headers.get("X-User-Name").and_then(|v| v.to_str().ok()) {
-        Some(v) => v,
-        None => return StatusCode::BAD_REQUEST.into_response(),
+    let user_name = match username(&headers) {
+        Ok(v) => v,
+        Err(e) => return e.into_response(),
     };
 
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-    let res = reservation::table
-        .filter(reservation::username.eq(user_name))
-        .inner_join(hotels::table)
-        .select((db_dto::Reservation::as_select(), db_dto::Hotel::as_select()))
-        .load(conn);
+    let res = state.reservations.reservations(user_name.to_owned()).await;
 
     match res {
         Ok(r) => (
@@ -147,7 +248,7 @@ pub async fn get_reservations(
             ),
         )
             .into_response(),
-        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        Err(e) => ApiError::from(e).into_response(),
     }
 }
 
@@ -161,6 +262,11 @@ pub async fn get_reservations(
             body = response_dto::ReservationWithHotel,
             content_type = "application/json",
         ),
+        (
+            status = NOT_FOUND,
+            description = "Бронирование не найдено или принадлежит другому пользователю",
+            body = ErrorResponse,
+        ),
     ),
     params(
         ("X-User-Name", Header, description = "Имя пользователя"),
@@ -171,25 +277,13 @@ pub async fn get_reservation(
     State(state): State<AppState>,
     Path(path): Path<request_dto::ReservationPath>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
 
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-    let (reservation, hotel) = reservation::table
-        .filter(reservation::username.eq(username))
-        .filter(reservation::reservation_uid.eq(path.reservation_uid))
-        .inner_join(hotels::table)
-        .select((db_dto::Reservation::as_select(), db_dto::Hotel::as_select()))
-        .get_result(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
+    let (reservation, hotel) = state
+        .reservations
+        .reservation(username.to_owned(), path.reservation_uid)
+        .await?;
 
     Ok((
         StatusCode::OK,
@@ -219,24 +313,13 @@ pub async fn delete_reservation(
     State(state): State<AppState>,
     Path(path): Path<request_dto::ReservationPath>,
     headers: HeaderMap,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
 
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
-    diesel::update(reservation::table)
-        .filter(reservation::username.eq(username))
-        .filter(reservation::reservation_uid.eq(path.reservation_uid))
-        .set(reservation::status.eq(response_dto::ReservationStatus::Canceled.to_string()))
-        .execute(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
+    state
+        .reservations
+        .cancel(username.to_owned(), path.reservation_uid)
+        .await?;
 
     Ok(StatusCode::NO_CONTENT)
 }
@@ -245,7 +328,8 @@ pub async fn delete_reservation(
     post,
     path = "/api/v1/reservations",
     responses(
-        (status = CREATED, body = response_dto::Reservation, description = "Success")
+        (status = CREATED, body = response_dto::Reservation, description = "Success"),
+        (status = BAD_REQUEST, description = "Недопустимые даты бронирования", body = ErrorResponse),
     ),
     params(
         ("X-User-Name", Header, description="Имя пользователя, для которого будет заведена бронь")
@@ -255,33 +339,15 @@ pub async fn post_reservation(
     State(state): State<AppState>,
     headers: HeaderMap,
     Json(reservation): Json<request_dto::ReservationRequest>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let username = headers
-        .get("X-User-Name")
-        .ok_or(StatusCode::BAD_REQUEST)?
-        .to_str()
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
-
-    let conn = &mut PgConnection::establish(state.database_url.as_str())
-        .expect("Failed to establish connection to database");
+) -> Result<impl IntoResponse, ApiError> {
+    let username = username(&headers)?;
+    reservation.validate_with_args(&state.validation.earliest_start())?;
 
     let hotel_uid = reservation.hotel_uid;
-
-    let id = hotels::table
-        .filter(hotels::hotel_uid.eq(hotel_uid))
-        .select(hotels::id)
-        .get_result(conn)
-        .map_err(|e| match e {
-            DieselError::NotFound => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
-        })?;
-
-    let post_reservation = reservation.into_db_dto(username.to_owned(), Some(id));
-    let created_reservation = diesel::insert_into(reservation::table)
-        .values(&post_reservation)
-        .returning(db_dto::Reservation::as_returning())
-        .get_result(conn)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let created_reservation = state
+        .reservations
+        .create(username.to_owned(), reservation)
+        .await?;
 
     let response_reservation =
         response_dto::Reservation::from_db_dto(created_reservation, hotel_uid);
@@ -1,2 +1,415 @@
 #[test]
 fn hello_world() {}
+
+/// The global logger can only be installed once per process, so tests share it
+fn logging() -> crate::logger::Logging {
+    static LOGGING: std::sync::OnceLock<crate::logger::Logging> = std::sync::OnceLock::new();
+    LOGGING
+        .get_or_init(|| {
+            let mut config = crate::config::Config::default().log;
+            config.level = "off".to_owned();
+            crate::logger::init(&config)
+        })
+        .clone()
+}
+
+#[test]
+fn config_takes_database_url_from_env() {
+    use crate::config::{Config, ConfigError};
+
+    assert!(matches!(
+        Config::from_layers(None, |_| None),
+        Err(ConfigError::Invalid(_))
+    ));
+
+    let env = |name: &str| (name == "DATABASE_URL").then(|| "postgres://db/test".to_owned());
+    let config = Config::from_layers(Some("[server]\nworker_threads = 2"), env).unwrap();
+    assert_eq!(config.database.url, "postgres://db/test");
+    assert_eq!(config.server.worker_threads, 2);
+}
+
+#[test]
+fn printed_config_hides_secrets() {
+    use crate::config::Config;
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://program:test@db/reservations".to_owned()),
+        "ADMIN_TOKEN" => Some("admin-token".to_owned()),
+        _ => None,
+    };
+    let printed = toml::to_string_pretty(&Config::from_layers(None, env).unwrap()).unwrap();
+    assert!(!printed.contains("program:test"));
+    assert!(!printed.contains("admin-token"));
+    assert!(printed.contains(r#"url = "***""#));
+    assert!(printed.contains(r#"token = "***""#));
+    // unset secrets are shown as such
+    assert!(printed.contains(r#"secret = """#));
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn database_wait_does_not_block_runtime() {
+    use std::time::{Duration, Instant};
+
+    use axum::http::StatusCode;
+
+    use crate::{config::Config, db, error::ApiError};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        "DATABASE_ACQUIRE_TIMEOUT_MS" => Some("300".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+
+    let ticker = tokio::spawn(async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Instant::now()
+    });
+    let res = db::run(&pool, "test", |_| Ok(())).await;
+    let finished = Instant::now();
+
+    assert_eq!(
+        ApiError::from(res.unwrap_err()).status,
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+    // the single runtime thread kept running other tasks while the pool was waiting
+    assert!(ticker.await.unwrap() < finished);
+    assert!(crate::metrics::metrics()
+        .render()
+        .contains(r#"db_connection_wait_seconds_count{query="test"} 1"#));
+}
+
+#[tokio::test]
+async fn startup_retries_until_database_is_available() {
+    use std::{
+        sync::{atomic::AtomicBool, Arc},
+        time::Duration,
+    };
+
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use tower::ServiceExt;
+
+    use crate::{config::Config, db};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        "DATABASE_ACQUIRE_TIMEOUT_MS" => Some("50".to_owned()),
+        "DATABASE_STARTUP_RETRY_INITIAL_MS" => Some("10".to_owned()),
+        "DATABASE_STARTUP_RETRY_MAX_MS" => Some("20".to_owned()),
+        _ => None,
+    };
+    let config = Config::from_layers(None, env).unwrap().database;
+    let pool = db::pool(&config);
+    let ready = Arc::new(AtomicBool::new(false));
+
+    let init = tokio::spawn(crate::init_db(pool.clone(), config, ready.clone()));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    // several attempts failed, but the task neither gave up nor panicked
+    assert!(!init.is_finished());
+    init.abort();
+
+    let resp = crate::app(
+        pool,
+        ready,
+        logging(),
+        None,
+        crate::admin::Admin::default(),
+        Config::default().validation,
+    )
+    .await
+    .oneshot(Request::get("/manage/ready").body(Body::empty()).unwrap())
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(report["status"], "DOWN");
+    assert_eq!(report["components"]["database"]["status"], "DOWN");
+    assert!(report["components"]["database"]["latencyMs"].is_number());
+    assert_eq!(report["components"]["migrations"]["status"], "DOWN");
+}
+
+#[tokio::test]
+async fn request_id_is_echoed_or_generated() {
+    use axum::{body::Body, http::Request};
+    use std::sync::{atomic::AtomicBool, Arc};
+    use tower::ServiceExt;
+
+    use crate::{config::Config, db};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+    let app = crate::app(
+        pool,
+        Arc::new(AtomicBool::new(false)),
+        logging(),
+        None,
+        crate::admin::Admin::default(),
+        Config::default().validation,
+    )
+    .await;
+
+    let resp = app
+        .clone()
+        .oneshot(
+            Request::get("/manage/health")
+                .header("X-Request-Id", "req-42")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["X-Request-Id"], "req-42");
+
+    let resp = app
+        .oneshot(Request::get("/manage/health").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert!(!resp.headers()["X-Request-Id"].is_empty());
+}
+
+#[test]
+fn unsigned_and_replayed_requests_are_rejected() {
+    use axum::http::{HeaderMap, HeaderValue};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use crate::{
+        config::Config,
+        signature::{self, Verifier},
+    };
+
+    let mut config = Config::default().signing;
+    assert!(Verifier::new(&config).is_none());
+    config.secret = "internal-secret".to_owned();
+    let verifier = Verifier::new(&config).unwrap();
+
+    let now = 1_700_000_000;
+    let sign = |secret: &str, timestamp: i64, nonce: &str, body: &[u8]| {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-User-Name", HeaderValue::from_static("Test Max"));
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(
+            signature::payload("PUT", "/api/v1/x?y=1", &headers, timestamp, nonce, body).as_bytes(),
+        );
+        headers.insert(
+            signature::SIGNATURE,
+            HeaderValue::from_str(&hex::encode(mac.finalize().into_bytes())).unwrap(),
+        );
+        headers.insert(signature::TIMESTAMP, HeaderValue::from(timestamp));
+        headers.insert(signature::NONCE, HeaderValue::from_str(nonce).unwrap());
+        headers
+    };
+    let verify = |headers: &HeaderMap, body: &[u8]| {
+        verifier.verify("PUT", "/api/v1/x?y=1", headers, body, now)
+    };
+
+    let headers = sign("internal-secret", now - 10, "n1", b"{}");
+    assert_eq!(verify(&headers, b"{}"), Ok(()));
+    assert!(verify(&headers, b"{}").is_err());
+
+    assert!(verify(&HeaderMap::new(), b"{}").is_err());
+    assert!(verify(&sign("internal-secret", now, "n2", b"{}"), b"{\"a\":1}").is_err());
+    assert!(verify(&sign("other-secret", now, "n3", b"{}"), b"{}").is_err());
+    assert!(verify(&sign("internal-secret", now - 301, "n4", b"{}"), b"{}").is_err());
+
+    // the user a request is made for can't be changed
+    let mut headers = sign("internal-secret", now, "n5", b"{}");
+    headers.insert("X-User-Name", HeaderValue::from_static("Other"));
+    assert!(verify(&headers, b"{}").is_err());
+    headers.remove("X-User-Name");
+    assert!(verify(&headers, b"{}").is_err());
+}
+
+#[tokio::test]
+async fn invalid_requests_list_every_field() {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        config::Config,
+        db,
+        error::{ErrorCode, ErrorResponse},
+    };
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+    let app = crate::app(
+        pool,
+        Arc::new(AtomicBool::new(true)),
+        logging(),
+        None,
+        crate::admin::Admin::default(),
+        Config::default().validation,
+    )
+    .await;
+    // the database is unreachable, so invalid requests must be rejected before it is queried
+    let fields = |req: Request<Body>| {
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: ErrorResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.code, ErrorCode::ValidationFailed);
+            body.details
+                .into_iter()
+                .map(|d| d.field)
+                .collect::<Vec<_>>()
+        }
+    };
+
+    let req = Request::get("/api/v1/hotels?page=0&size=1000")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(fields(req).await, ["page", "size"]);
+
+    let reservation = serde_json::json!({
+        "hotelUid": uuid::Uuid::new_v4(),
+        "paymentUid": uuid::Uuid::new_v4(),
+        "startDate": "2021-10-11T00:00:00+03:00",
+        "endDate": "2021-10-08T00:00:00+03:00",
+    });
+    let req = Request::post("/api/v1/reservations")
+        .header("X-User-Name", "Test Max")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(reservation.to_string()))
+        .unwrap();
+    assert_eq!(fields(req).await, ["endDate", "startDate"]);
+}
+
+#[tokio::test]
+async fn malformed_requests_are_rejected_with_error_body() {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use axum::{
+        body::Body,
+        http::{header, Request, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use crate::{
+        config::Config,
+        db,
+        error::{ErrorCode, ErrorResponse},
+    };
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        _ => None,
+    };
+    let pool = db::pool(&Config::from_layers(None, env).unwrap().database);
+    let app = crate::app(
+        pool,
+        Arc::new(AtomicBool::new(true)),
+        logging(),
+        None,
+        crate::admin::Admin::default(),
+        Config::default().validation,
+    )
+    .await;
+    let rejected = |req: Request<Body>| {
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "application/json");
+            let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: ErrorResponse = serde_json::from_slice(&body).unwrap();
+            assert_eq!(body.code, ErrorCode::BadRequest);
+        }
+    };
+
+    rejected(
+        Request::post("/api/v1/reservations")
+            .header("X-User-Name", "Test Max")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{\"hotelUid\":"))
+            .unwrap(),
+    )
+    .await;
+    rejected(
+        Request::get("/api/v1/hotels?page=first")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    rejected(
+        Request::get("/api/v1/hotel/not-a-uuid")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn log_levels_change_requires_admin_token() {
+    use axum::{body::Body, http::Request, http::StatusCode};
+    use std::sync::{atomic::AtomicBool, Arc};
+    use tower::ServiceExt;
+
+    use crate::{admin::Admin, config::Config, db};
+
+    let env = |name: &str| match name {
+        "DATABASE_URL" => Some("postgres://127.0.0.1:1/unreachable".to_owned()),
+        "DATABASE_MIN_IDLE" => Some("0".to_owned()),
+        "ADMIN_TOKEN" => Some("admin-token".to_owned()),
+        _ => None,
+    };
+    let config = Config::from_layers(None, env).unwrap();
+    let app = crate::app(
+        db::pool(&config.database),
+        Arc::new(AtomicBool::new(false)),
+        logging(),
+        None,
+        Admin::new(&config.admin),
+        Config::default().validation,
+    )
+    .await;
+    let put = |token: Option<&str>| {
+        let mut req = Request::put("/manage/log-levels").header("Content-Type", "application/json");
+        if let Some(token) = token {
+            req = req.header("Authorization", format!("Bearer {token}"));
+        }
+        req.body(Body::from("{}")).unwrap()
+    };
+
+    for token in [None, Some("user-token")] {
+        let resp = app.clone().oneshot(put(token)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+    let resp = app.clone().oneshot(put(Some("admin-token"))).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    // reads stay open
+    let resp = app
+        .oneshot(
+            Request::get("/manage/log-levels")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}